@@ -0,0 +1,472 @@
+//! Files written next to the rom that let emulators and debuggers relate
+//! it back to the source.
+
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::{
+    json,
+    lexer::Span,
+    parser::Stmt_::{CallLabel, DeclareConstant, DeclareLabel, DeclareSprite, Return, Word},
+    source_map::SourceMap,
+    xref::{cross_reference, Kind},
+    Assembly,
+};
+
+fn span_fields(object: json::Object, source_map: &SourceMap, span: Span) -> json::Object {
+    let (start, end) = source_map.span_positions(span);
+    object
+        .num("line", start.line)
+        .num("column", start.column)
+        .num("end_line", end.line)
+        .num("end_column", end.column)
+        .num("lo", span.lo)
+        .num("hi", span.hi)
+}
+
+/// A JSON source map: every instruction's address and size, and every
+/// sprite's data range, with the position of the statement that produced
+/// it in `file_name`. Lines and columns are 1-based, columns in
+/// characters; `lo` and `hi` are byte offsets.
+pub fn source_map(assembly: &Assembly, file_name: &str, source: &str) -> String {
+    let source_map = SourceMap::new(source);
+
+    let instructions = assembly.instructions().map(|(address, stmt)| {
+        let object = json::Object::new()
+            .num("address", address)
+            .num("size", stmt.node.size());
+        span_fields(object, &source_map, stmt.span).build()
+    });
+
+    let sprites = assembly
+        .addresses
+        .iter()
+        .zip(&assembly.program.statements)
+        .filter_map(|(address, stmt)| match stmt.node {
+            DeclareSprite(ref name, ref data) => {
+                let object = json::Object::new()
+                    .str("name", name)
+                    .num("address", address)
+                    .num("size", data.len());
+                Some(span_fields(object, &source_map, stmt.span).build())
+            }
+            _ => None,
+        });
+
+    json::Object::new()
+        .num("version", 1)
+        .str("file", file_name)
+        .num("rom_size", assembly.rom.len())
+        .field("instructions", json::array(instructions))
+        .field("sprites", json::array(sprites))
+        .build()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolFormat {
+    /// `0x200 main code`, one symbol per line.
+    Text,
+    /// `:const main 0x200`, which Octo reads as source.
+    Octo,
+}
+
+/// Every label and sprite ordered by address, code labels as kind `code`
+/// and sprites as kind `sprite`.
+pub fn symbols(assembly: &Assembly, format: SymbolFormat) -> String {
+    let labels = assembly
+        .labels
+        .iter()
+        .map(|(name, address)| (address, name, "code"));
+    let sprites = assembly
+        .sprites
+        .iter()
+        .map(|(name, address)| (address, name, "sprite"));
+    let mut symbols: Vec<_> = labels.chain(sprites).collect();
+    symbols.sort();
+
+    let mut out = String::new();
+    for (address, name, kind) in symbols {
+        let _ = match format {
+            SymbolFormat::Text => writeln!(out, "{:#05x} {} {}", address, name, kind),
+            SymbolFormat::Octo => writeln!(out, ":const {} {:#05x} # {}", name, address, kind),
+        };
+    }
+    out
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    bytes.join(" ")
+}
+
+/// A classic assembler listing: every source line with the address and
+/// bytes it produced, then the sprite data at its real address drawn as
+/// pixels, then a symbol table saying where each name is used.
+pub fn listing(assembly: &Assembly, file_name: &str, source: &str) -> String {
+    let source_map = SourceMap::new(source);
+    let line_of = |span: Span| source_map.position(span.lo).line;
+    let rom_bytes = |address: u16, len: usize| {
+        let offset = address as usize - assembly.target.load_address();
+        &assembly.rom[offset..offset + len]
+    };
+
+    // the parser takes one statement per line
+    let mut by_line = BTreeMap::new();
+    for (address, stmt) in assembly.addresses.iter().zip(&assembly.program.statements) {
+        by_line.insert(line_of(stmt.span), (*address, stmt));
+    }
+
+    let mut out = String::new();
+    let _ = writeln!(out, "; listing of {}\n", file_name);
+    // wide enough for the 4 bytes of `mov i,long`
+    let _ = writeln!(out, "address  bytes        line  source");
+    for line in 1..=source_map.line_count() {
+        let text = source_map.line_text(line);
+        if line == source_map.line_count() && text.is_empty() {
+            break;
+        }
+        let (address, bytes) = match by_line.get(&line) {
+            Some((address, stmt)) => match stmt.node {
+                // sprite data is listed with the rest after the code
+                DeclareLabel(_) | DeclareSprite(..) => (format!("{:#05x}", address), String::new()),
+                DeclareConstant(..) => (String::new(), String::new()),
+                _ => (
+                    format!("{:#05x}", address),
+                    hex_bytes(rom_bytes(*address, stmt.node.size())),
+                ),
+            },
+            None => (String::new(), String::new()),
+        };
+        let _ = writeln!(out, "{:<7}  {:<11}  {:>4}  {}", address, bytes, line, text);
+    }
+
+    let mut sprites: Vec<(u16, &str, usize)> = assembly
+        .addresses
+        .iter()
+        .zip(&assembly.program.statements)
+        .filter_map(|(address, stmt)| match stmt.node {
+            DeclareSprite(ref name, ref data) => Some((*address, name.as_str(), data.len())),
+            _ => None,
+        })
+        .collect();
+    sprites.sort();
+
+    if !sprites.is_empty() {
+        let _ = writeln!(out, "\n; sprite data\n");
+        for (address, name, len) in sprites {
+            let _ = writeln!(out, "{:#05x}  ${}", address, name);
+            for (row, byte) in rom_bytes(address, len).iter().enumerate() {
+                let pixels: String = (0..8)
+                    .map(|bit| if byte & (0x80 >> bit) != 0 { '#' } else { '.' })
+                    .collect();
+                let _ = writeln!(
+                    out,
+                    "{:#05x}  {:02x}      {}",
+                    address as usize + row,
+                    byte,
+                    pixels
+                );
+            }
+        }
+    }
+
+    let _ = writeln!(out, "\n; symbols\n");
+    let _ = writeln!(
+        out,
+        "{:<20}  {:<8}  address  defined  used on lines",
+        "name", "kind"
+    );
+    for symbol in cross_reference(&assembly.program) {
+        // a constant's value goes in the address column
+        let table = match symbol.kind {
+            Kind::Label => &assembly.labels,
+            Kind::Sprite => &assembly.sprites,
+            Kind::Constant => &assembly.constants,
+            // only programs that failed to assemble have these
+            Kind::Unknown => continue,
+        };
+        let address = table
+            .get(symbol.name)
+            .map_or(String::new(), |address| format!("{:#05x}", address));
+        let defined = symbol
+            .definitions
+            .first()
+            .map_or(String::new(), |span| line_of(*span).to_string());
+        let used: Vec<String> = symbol
+            .references
+            .iter()
+            .map(|span| line_of(*span).to_string())
+            .collect();
+        let used = if used.is_empty() {
+            "unused".to_string()
+        } else {
+            used.join(", ")
+        };
+        let _ = writeln!(
+            out,
+            "{:<20}  {:<8}  {:<7}  {:<7}  {}",
+            symbol.name,
+            symbol.kind.name(),
+            address,
+            defined,
+            used
+        );
+    }
+
+    out
+}
+
+fn bytes(count: usize) -> String {
+    match count {
+        1 => "1 byte".to_string(),
+        _ => format!("{} bytes", count),
+    }
+}
+
+/// How many of the largest subroutines and sprites `memory_map` lists.
+const LARGEST: usize = 5;
+
+/// How the rom uses memory: the code and sprite sections, how much of the
+/// code section is `dw` data, what is left, and the largest subroutines
+/// and sprites.
+pub fn memory_map(assembly: &Assembly) -> String {
+    let sprites: Vec<(u16, &str, usize)> = assembly
+        .addresses
+        .iter()
+        .zip(&assembly.program.statements)
+        .filter_map(|(address, stmt)| match stmt.node {
+            DeclareSprite(ref name, ref data) => Some((*address, name.as_str(), data.len())),
+            _ => None,
+        })
+        .collect();
+    let target = assembly.target;
+    let start = target.entry();
+    let end = target.load_address() + assembly.rom.len();
+    let sprite_size: usize = sprites.iter().map(|(_, _, len)| len).sum();
+    // sprites always go after the code, and `dw` words sit in among it
+    let code_end = end - sprite_size;
+    let data_size: usize = assembly
+        .instructions()
+        .filter(|(_, stmt)| matches!(stmt.node, Word(_)))
+        .map(|(_, stmt)| stmt.node.size())
+        .sum();
+    let used = end - start;
+    let memory_size = target.memory_size();
+    let available = memory_size - start;
+
+    let mut out = String::new();
+    let _ = writeln!(out, "; memory map\n");
+    // lined up for the widest range, which is where memory ends
+    let width = format!("{:#05x}-{:#05x}", 0, memory_size - 1).len();
+    let mut section = |name: &str, start: usize, end: usize| {
+        if start < end {
+            let range = format!("{:#05x}-{:#05x}", start, end - 1);
+            let _ = writeln!(
+                out,
+                "{:<width$}  {:<12}  {}",
+                range,
+                name,
+                bytes(end - start)
+            );
+        }
+    };
+    for region in target.reserved() {
+        section(region.name, region.start, region.end);
+    }
+    section("code", start, code_end);
+    section("sprites", code_end, end);
+    section("free", end, memory_size);
+    let _ = writeln!(
+        out,
+        "\nused {} of {} ({:.1}%), {} free",
+        bytes(used),
+        available,
+        used as f64 * 100.0 / available as f64,
+        bytes(memory_size.saturating_sub(end))
+    );
+    let _ = writeln!(
+        out,
+        "code {}, data {} in among the code, sprites {}",
+        bytes(code_end - start - data_size),
+        bytes(data_size),
+        bytes(sprite_size)
+    );
+
+    // a subroutine runs from its label to its last `ret` before the next
+    // subroutine, or to the next subroutine when there is none
+    let xref = cross_reference(&assembly.program);
+    let called: Vec<&str> = assembly
+        .program
+        .statements
+        .iter()
+        .filter_map(|stmt| match stmt.node {
+            CallLabel(ref name) => Some(name.as_str()),
+            _ => None,
+        })
+        .collect();
+    let returns: Vec<usize> = assembly
+        .instructions()
+        .filter(|(_, stmt)| stmt.node == Return)
+        .map(|(address, _)| address as usize)
+        .collect();
+    let mut starts: Vec<(u16, &str)> = xref
+        .iter()
+        .filter(|symbol| symbol.kind == Kind::Label && called.contains(&symbol.name))
+        .filter_map(|symbol| Some((*assembly.labels.get(symbol.name)?, symbol.name)))
+        .collect();
+    starts.sort();
+    let mut subroutines: Vec<(usize, u16, &str)> = starts
+        .iter()
+        .enumerate()
+        .map(|(i, (address, name))| {
+            let next = starts
+                .get(i + 1)
+                .map_or(code_end, |(next, _)| *next as usize);
+            let end = returns
+                .iter()
+                .filter(|ret| (*address as usize..next).contains(ret))
+                .max()
+                .map_or(next, |ret| ret + 2);
+            (end - *address as usize, *address, *name)
+        })
+        .collect();
+    subroutines.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+
+    if !subroutines.is_empty() {
+        let _ = writeln!(out, "\n; largest subroutines\n");
+        for (size, address, name) in subroutines.into_iter().take(LARGEST) {
+            let _ = writeln!(out, "{:#05x}  {:<20}  {}", address, name, bytes(size));
+        }
+    }
+
+    let mut sprites = sprites;
+    sprites.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(&b.0)));
+    if !sprites.is_empty() {
+        let _ = writeln!(out, "\n; largest sprites\n");
+        for (address, name, size) in sprites.into_iter().take(LARGEST) {
+            let _ = writeln!(out, "{:#05x}  {:<20}  {}", address, name, bytes(size));
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble;
+
+    const PROGRAM: &str = "\
+$ball 0x60 0x90
+start:
+    mov  i,ball
+    drw  v0,v1,2
+    jmp  start
+";
+
+    fn assembly(source: &str) -> Assembly {
+        let Ok(assembly) = assemble(source) else {
+            panic!("{:?} failed", source);
+        };
+        assembly
+    }
+
+    #[test]
+    fn source_map_positions() {
+        let instruction = |address, line, end_column, lo, hi| {
+            format!(
+                r#"{{"address":{},"size":2,"line":{},"column":5,"end_line":{},"end_column":{},"lo":{},"hi":{}}}"#,
+                address, line, line, end_column, lo, hi
+            )
+        };
+        assert_eq!(
+            source_map(&assembly(PROGRAM), "test.s", PROGRAM),
+            format!(
+                r#"{{"version":1,"file":"test.s","rom_size":8,"instructions":[{},{},{}],"sprites":[{}]}}"#,
+                instruction(512, 3, 16, 27, 38),
+                instruction(514, 4, 17, 43, 55),
+                instruction(516, 5, 15, 60, 70),
+                r#"{"name":"ball","address":518,"size":2,"line":1,"column":1,"end_line":1,"end_column":16,"lo":0,"hi":15}"#
+            )
+        );
+    }
+
+    #[test]
+    fn symbol_files() {
+        let assembly = assembly(PROGRAM);
+        assert_eq!(
+            symbols(&assembly, SymbolFormat::Text),
+            "0x200 start code\n0x206 ball sprite\n"
+        );
+        // Octo reads `#` to the end of the line as a comment
+        assert_eq!(
+            symbols(&assembly, SymbolFormat::Octo),
+            ":const start 0x200 # code\n:const ball 0x206 # sprite\n"
+        );
+    }
+
+    #[test]
+    fn listing_columns() {
+        assert_eq!(
+            listing(&assembly(PROGRAM), "test.s", PROGRAM),
+            "\
+; listing of test.s
+
+address  bytes        line  source
+0x206                    1  $ball 0x60 0x90
+0x200                    2  start:
+0x200    a2 06           3      mov  i,ball
+0x202    d0 12           4      drw  v0,v1,2
+0x204    12 00           5      jmp  start
+
+; sprite data
+
+0x206  $ball
+0x206  60      .##.....
+0x207  90      #..#....
+
+; symbols
+
+name                  kind      address  defined  used on lines
+start                 label     0x200    2        5
+ball                  sprite    0x206    1        3
+"
+        );
+    }
+
+    #[test]
+    fn memory_map_sections() {
+        let source = "\
+$ball 0x60 0x90
+    call draw
+    dw   0x1234
+loop:
+    jmp  loop
+draw:
+    mov  i,ball
+    drw  v0,v1,2
+    ret
+";
+        assert_eq!(
+            memory_map(&assembly(source)),
+            "\
+; memory map
+
+0x000-0x1ff  interpreter   512 bytes
+0x200-0x20b  code          12 bytes
+0x20c-0x20d  sprites       2 bytes
+0x20e-0xfff  free          3570 bytes
+
+used 14 bytes of 3584 (0.4%), 3570 bytes free
+code 10 bytes, data 2 bytes in among the code, sprites 2 bytes
+
+; largest subroutines
+
+0x206  draw                  6 bytes
+
+; largest sprites
+
+0x20c  ball                  2 bytes
+"
+        );
+    }
+}
@@ -0,0 +1,370 @@
+//! `chirp debug`: a line-based debugger over the emulator that shows
+//! where execution is in the chirp source.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+    io::{self, BufRead, Write},
+};
+
+use chirp::{
+    disasm,
+    emu::{Fault, Machine},
+    lexer::Span,
+    source_map::SourceMap,
+    Assembly,
+};
+
+const HELP: &str = "commands:
+    break <label|line|0xaddr>   stop before running that instruction (b)
+    delete <label|line|0xaddr>  remove a breakpoint
+    watch <label|sprite|addr>   stop when the byte at an address changes
+    unwatch <label|sprite|addr> remove a watchpoint
+    step                        run one instruction (s)
+    next                        run one instruction, stepping over calls (n)
+    finish                      run until the current subroutine returns
+    continue                    run until a breakpoint, watchpoint or fault (c)
+    regs                        show registers, timers and the stack (r)
+    mem <addr|label|sprite> [n] dump n bytes of memory, 16 by default (x)
+    key <0-f> [up]              press or release a keypad key
+    list                        show breakpoints and watchpoints
+    quit                        leave the debugger (q)";
+
+/// Why running stopped.
+enum Stop {
+    /// The condition the command was waiting for.
+    Done,
+    Breakpoint,
+    Watch {
+        address: u16,
+        old: u8,
+        new: u8,
+    },
+    Fault(Fault),
+    /// The instruction didn't move pc: a `wait` with no key, or a jump to
+    /// itself.
+    Stuck,
+}
+
+struct Debugger<'a> {
+    machine: Machine,
+    assembly: &'a Assembly,
+    file_name: &'a str,
+    source_map: SourceMap<'a>,
+    /// The statement at each instruction address.
+    spans: BTreeMap<u16, Span>,
+    breakpoints: BTreeSet<u16>,
+    /// Watched addresses and the value last seen there.
+    watches: BTreeMap<u16, u8>,
+    /// Instructions run since the timers last ticked.
+    steps: usize,
+}
+
+impl<'a> Debugger<'a> {
+    /// Where `address` is in the source, as `file:line: text`.
+    fn source_line(&self, address: u16) -> Option<String> {
+        let span = self.spans.get(&address)?;
+        let line = self.source_map.position(span.lo).line;
+        Some(format!(
+            "{}:{}: {}",
+            self.file_name,
+            line,
+            self.source_map.line_text(line).trim()
+        ))
+    }
+
+    fn location(&self) -> String {
+        let pc = self.machine.pc;
+        match self.source_line(pc) {
+            Some(line) => format!("{:#05x}  {}", pc, line),
+            // outside the assembled code, so show what is there instead
+            None => {
+                let opcode = self.machine.opcode();
+                match disasm::decode_at(&self.machine.memory, pc as usize) {
+                    Some(stmt) => format!("{:#05x}  {:04x}  {} (no source)", pc, opcode, stmt),
+                    None => format!("{:#05x}  {:04x}  (no source)", pc, opcode),
+                }
+            }
+        }
+    }
+
+    /// An address given as `0x` hex, or a label or sprite name.
+    fn address(&self, arg: &str) -> Result<u16, String> {
+        if let Some(hex) = arg.strip_prefix("0x") {
+            return match u16::from_str_radix(hex, 16) {
+                Ok(address) if (address as usize) < self.machine.memory.len() => Ok(address),
+                _ => Err(format!("invalid address {:?}", arg)),
+            };
+        }
+        if let Ok(address) = arg.parse::<u16>() {
+            if (address as usize) < self.machine.memory.len() {
+                return Ok(address);
+            }
+        }
+        self.assembly
+            .labels
+            .get(arg)
+            .or_else(|| self.assembly.sprites.get(arg))
+            .copied()
+            .ok_or_else(|| format!("no label or sprite named {:?}", arg))
+    }
+
+    /// A breakpoint location: a label, a source line number or a `0x`
+    /// address.
+    fn breakpoint(&self, arg: &str) -> Result<u16, String> {
+        let Ok(line) = arg.parse::<usize>() else {
+            return self.address(arg);
+        };
+
+        self.spans
+            .iter()
+            .find(|(_, span)| self.source_map.position(span.lo).line == line)
+            .map(|(address, _)| *address)
+            .ok_or_else(|| format!("no instruction on line {}", line))
+    }
+
+    fn step(&mut self) -> Result<(), Fault> {
+        self.machine.step()?;
+        self.steps += 1;
+        if self.steps >= self.machine.speed {
+            self.machine.tick_timers();
+            self.steps = 0;
+        }
+        Ok(())
+    }
+
+    /// Steps at least once, until `done` holds or something else stops it.
+    fn run_until(&mut self, done: impl Fn(&Machine) -> bool) -> Stop {
+        loop {
+            let pc = self.machine.pc;
+            if let Err(fault) = self.step() {
+                return Stop::Fault(fault);
+            }
+
+            for (&address, old) in self.watches.iter_mut() {
+                let new = self.machine.memory[address as usize];
+                if new != *old {
+                    let stop = Stop::Watch {
+                        address,
+                        old: *old,
+                        new,
+                    };
+                    *old = new;
+                    return stop;
+                }
+            }
+
+            if done(&self.machine) {
+                return Stop::Done;
+            }
+            if self.breakpoints.contains(&self.machine.pc) {
+                return Stop::Breakpoint;
+            }
+            if self.machine.pc == pc {
+                return Stop::Stuck;
+            }
+        }
+    }
+
+    fn report(&self, stop: Stop) {
+        match stop {
+            Stop::Done => {}
+            Stop::Breakpoint => println!("breakpoint"),
+            Stop::Watch { address, old, new } => println!(
+                "watchpoint {:#05x} changed from {:#04x} to {:#04x}",
+                address, old, new
+            ),
+            Stop::Fault(fault) => println!("stopped: {}", fault),
+            Stop::Stuck => println!("stopped: this instruction waits for a key or jumps to itself"),
+        }
+        println!("{}", self.location());
+    }
+
+    fn registers(&self) -> String {
+        let machine = &self.machine;
+        let mut out = String::new();
+        for (offset, values) in machine.v.chunks(8).enumerate() {
+            let registers: Vec<String> = values
+                .iter()
+                .enumerate()
+                .map(|(x, value)| format!("v{:<2} {:#04x}", offset * 8 + x, value))
+                .collect();
+            let _ = writeln!(out, "{}", registers.join("  "));
+        }
+        let _ = writeln!(
+            out,
+            "i   {:#05x}  pc  {:#05x}  dt  {}  st  {}",
+            machine.i, machine.pc, machine.delay_timer, machine.sound_timer
+        );
+
+        let stack: Vec<String> = machine
+            .stack
+            .iter()
+            .rev()
+            .map(|address| match self.source_line(address.wrapping_sub(2)) {
+                Some(line) => format!("    {:#05x}  {}", address, line),
+                None => format!("    {:#05x}", address),
+            })
+            .collect();
+        if stack.is_empty() {
+            let _ = write!(out, "stack empty");
+        } else {
+            let _ = write!(out, "stack, innermost first:\n{}", stack.join("\n"));
+        }
+        out
+    }
+
+    fn dump(&self, start: u16, len: usize) -> String {
+        let end = (start as usize + len).min(self.machine.memory.len());
+        let mut out = String::new();
+        for row in (start as usize..end).step_by(16) {
+            let bytes: Vec<String> = self.machine.memory[row..(row + 16).min(end)]
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            let _ = writeln!(out, "{:#05x}  {}", row, bytes.join(" "));
+        }
+        out.trim_end().to_string()
+    }
+
+    /// Runs one command, returning false to quit.
+    fn command(&mut self, line: &str) -> Result<bool, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, args)) = words.split_first() else {
+            return Ok(true);
+        };
+        let arg = |index: usize| {
+            args.get(index)
+                .copied()
+                .ok_or_else(|| format!("{} expects an argument", command))
+        };
+
+        match command {
+            "break" | "b" => {
+                let address = self.breakpoint(arg(0)?)?;
+                self.breakpoints.insert(address);
+                println!("breakpoint at {}", self.describe(address));
+            }
+            "delete" => {
+                let address = self.breakpoint(arg(0)?)?;
+                if !self.breakpoints.remove(&address) {
+                    return Err(format!("no breakpoint at {:#05x}", address));
+                }
+            }
+            "watch" => {
+                let address = self.address(arg(0)?)?;
+                self.watches
+                    .insert(address, self.machine.memory[address as usize]);
+                println!("watching {:#05x}", address);
+            }
+            "unwatch" => {
+                let address = self.address(arg(0)?)?;
+                if self.watches.remove(&address).is_none() {
+                    return Err(format!("no watchpoint at {:#05x}", address));
+                }
+            }
+            "step" | "s" => {
+                let stop = self.run_until(|_| true);
+                self.report(stop);
+            }
+            "next" | "n" => {
+                let depth = self.machine.stack.len();
+                let stop = if self.machine.opcode() & 0xF000 == 0x2000 {
+                    let after = self.machine.wrap(self.machine.pc as usize + 2);
+                    self.run_until(|machine| machine.pc == after && machine.stack.len() == depth)
+                } else {
+                    self.run_until(|_| true)
+                };
+                self.report(stop);
+            }
+            "finish" => {
+                let depth = self.machine.stack.len();
+                if depth == 0 {
+                    return Err("not inside a subroutine".to_string());
+                }
+                let stop = self.run_until(|machine| machine.stack.len() < depth);
+                self.report(stop);
+            }
+            "continue" | "c" => {
+                let stop = self.run_until(|_| false);
+                self.report(stop);
+            }
+            "regs" | "r" => println!("{}", self.registers()),
+            "mem" | "x" => {
+                let address = self.address(arg(0)?)?;
+                let len = match args.get(1) {
+                    Some(len) => len
+                        .parse()
+                        .map_err(|_| format!("invalid length {:?}", len))?,
+                    None => 16,
+                };
+                println!("{}", self.dump(address, len));
+            }
+            "key" => {
+                let key = u8::from_str_radix(arg(0)?, 16)
+                    .ok()
+                    .filter(|key| *key < 16)
+                    .ok_or_else(|| format!("invalid key {:?}", args[0]))?;
+                let pressed = args.get(1) != Some(&"up");
+                self.machine.set_key(key, pressed);
+            }
+            "list" => {
+                for address in &self.breakpoints {
+                    println!("breakpoint {}", self.describe(*address));
+                }
+                for (address, value) in &self.watches {
+                    println!("watchpoint {:#05x} = {:#04x}", address, value);
+                }
+            }
+            "help" | "h" => println!("{}", HELP),
+            "quit" | "q" => return Ok(false),
+            _ => return Err(format!("unknown command {:?}, try help", command)),
+        }
+
+        Ok(true)
+    }
+
+    fn describe(&self, address: u16) -> String {
+        match self.source_line(address) {
+            Some(line) => format!("{:#05x}  {}", address, line),
+            None => format!("{:#05x}", address),
+        }
+    }
+}
+
+/// Reads commands from stdin until `quit` or end of input.
+pub fn debug(machine: Machine, assembly: &Assembly, file_name: &str, source: &str) {
+    let spans = assembly
+        .instructions()
+        .map(|(address, stmt)| (address, stmt.span))
+        .collect();
+    let mut debugger = Debugger {
+        machine,
+        assembly,
+        file_name,
+        source_map: SourceMap::new(source),
+        spans,
+        breakpoints: BTreeSet::new(),
+        watches: BTreeMap::new(),
+        steps: 0,
+    };
+
+    println!("{}", debugger.location());
+    let stdin = io::stdin();
+    loop {
+        print!("(chirp) ");
+        let _ = io::stdout().flush();
+
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+
+        match debugger.command(&line) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err) => println!("{}", err),
+        }
+    }
+}
@@ -0,0 +1,338 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+};
+
+use crate::{
+    disasm::{analyse, Analysis, Disassembly, Line},
+    parser::Stmt_::{self, *},
+};
+
+/// A structured construct recovered from the control-flow graph, covering
+/// the lines in `start..end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Construct {
+    /// A skip at `start` over a forward jump to `end`: the lines between
+    /// only run when the skip is taken.
+    If { start: usize, end: usize },
+    /// A backward jump at `end - 2` to `start`, optionally guarded by a
+    /// skip just before it.
+    Loop { start: usize, end: usize },
+}
+
+impl Construct {
+    fn range(self) -> (usize, usize) {
+        match self {
+            Construct::If { start, end } | Construct::Loop { start, end } => (start, end),
+        }
+    }
+
+    fn nests_with(self, other: Construct) -> bool {
+        let (a, b) = self.range();
+        let (c, d) = other.range();
+        b <= c || d <= a || (a <= c && d <= b) || (c <= a && b <= d)
+    }
+}
+
+/// What a skip instruction tests; the next instruction runs when it's false.
+fn condition(stmt: &Stmt_) -> Option<String> {
+    let condition = match *stmt {
+        SkipEqualsInteger(x, nn) => format!("v{} == {:#04x}", x, nn),
+        SkipNotEqualsInteger(x, nn) => format!("v{} != {:#04x}", x, nn),
+        SkipEqualsRegister(x, y) => format!("v{} == v{}", x, y),
+        SkipNotEqualsRegister(x, y) => format!("v{} != v{}", x, y),
+        SkipKeyPressed(x) => format!("key v{} is pressed", x),
+        SkipKeyNotPressed(x) => format!("key v{} is not pressed", x),
+        _ => return None,
+    };
+    Some(condition)
+}
+
+fn negated_condition(stmt: &Stmt_) -> Option<String> {
+    let negated = match *stmt {
+        SkipEqualsInteger(x, nn) => SkipNotEqualsInteger(x, nn),
+        SkipNotEqualsInteger(x, nn) => SkipEqualsInteger(x, nn),
+        SkipEqualsRegister(x, y) => SkipNotEqualsRegister(x, y),
+        SkipNotEqualsRegister(x, y) => SkipEqualsRegister(x, y),
+        SkipKeyPressed(x) => SkipKeyNotPressed(x),
+        SkipKeyNotPressed(x) => SkipKeyPressed(x),
+        _ => return None,
+    };
+    condition(&negated)
+}
+
+struct Decompiler<'a> {
+    analysis: &'a Analysis,
+    /// Reachable instructions by address.
+    code: BTreeMap<usize, &'a Stmt_>,
+    /// Subroutine entry points and where they are called from.
+    subroutines: BTreeMap<usize, Vec<usize>>,
+    loop_headers: BTreeSet<usize>,
+    constructs: Vec<Construct>,
+}
+
+impl<'a> Decompiler<'a> {
+    fn new(analysis: &'a Analysis) -> Self {
+        let code: BTreeMap<usize, &Stmt_> = analysis
+            .lines
+            .iter()
+            .filter(|line| line.reachable)
+            .filter_map(|line| Some((line.address, line.stmt.as_ref()?)))
+            .collect();
+
+        let mut subroutines: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        let mut candidates = vec![];
+
+        for (&address, stmt) in &code {
+            match **stmt {
+                CallInteger(nnn) if analysis.labels.contains(&(nnn as usize)) => {
+                    subroutines.entry(nnn as usize).or_default().push(address);
+                }
+                JumpInteger(nnn) if analysis.labels.contains(&(nnn as usize)) => {
+                    let target = nnn as usize;
+                    let guarded = address
+                        .checked_sub(2)
+                        .and_then(|previous| code.get(&previous))
+                        .is_some_and(|previous| condition(previous).is_some());
+
+                    if target <= address {
+                        candidates.push(Construct::Loop {
+                            start: target,
+                            end: address + 2,
+                        });
+                    } else if guarded {
+                        // a skip over a forward jump is an if around what follows
+                        candidates.push(Construct::If {
+                            start: address - 2,
+                            end: target,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // outer constructs first, so inner ones are checked against them
+        candidates.sort_by_key(|construct| {
+            let (start, end) = construct.range();
+            (start, usize::MAX - end)
+        });
+
+        let mut constructs: Vec<Construct> = vec![];
+        for candidate in candidates {
+            if constructs
+                .iter()
+                .all(|accepted| accepted.nests_with(candidate))
+            {
+                constructs.push(candidate);
+            }
+        }
+
+        let loop_headers = constructs
+            .iter()
+            .filter_map(|construct| match construct {
+                Construct::Loop { start, .. } => Some(*start),
+                Construct::If { .. } => None,
+            })
+            .collect();
+
+        Self {
+            analysis,
+            code,
+            subroutines,
+            loop_headers,
+            constructs,
+        }
+    }
+
+    fn name(&self, address: usize) -> String {
+        if self.subroutines.contains_key(&address) {
+            format!("sub_{:03x}", address)
+        } else if self.loop_headers.contains(&address) {
+            format!("loop_{:03x}", address)
+        } else {
+            format!("label_{:03x}", address)
+        }
+    }
+
+    /// How many constructs enclose the body of the line at `address`.
+    fn depth(&self, address: usize) -> usize {
+        self.constructs
+            .iter()
+            .filter(|construct| match **construct {
+                // the skip and its jump are the head of the if, not its body
+                Construct::If { start, end } => start + 4 <= address && address < end,
+                Construct::Loop { start, end } => start <= address && address < end,
+            })
+            .count()
+    }
+
+    /// Comment explaining how the instruction at `line` fits the structure.
+    fn annotation(&self, line: &Line) -> Option<String> {
+        let stmt = self.code.get(&line.address)?;
+
+        for construct in &self.constructs {
+            match *construct {
+                Construct::If { start, .. } if start == line.address => {
+                    return Some(format!("if {} {{", condition(stmt)?));
+                }
+                Construct::If { start, end } if start + 2 == line.address => {
+                    return Some(format!("otherwise skip to {}", self.name(end)));
+                }
+                Construct::Loop { start, end } if end - 2 == line.address => {
+                    let guard = self
+                        .code
+                        .get(&(line.address - 2))
+                        .and_then(|guard| negated_condition(guard))
+                        .filter(|_| start < line.address);
+                    return Some(match guard {
+                        Some(guard) => format!("}} while {}", guard),
+                        None => "}".to_string(),
+                    });
+                }
+                // the guard of a `while` is described where the loop closes
+                Construct::Loop { start, end }
+                    if start <= line.address
+                        && end - 4 == line.address
+                        && condition(stmt).is_some() =>
+                {
+                    return None;
+                }
+                _ => {}
+            }
+        }
+
+        match **stmt {
+            JumpInteger(nnn) => {
+                let previous = self.code.get(&(line.address.checked_sub(2)?));
+                let guard = previous.and_then(|previous| negated_condition(previous))?;
+                Some(format!("if {} goto {}", guard, self.name(nnn as usize)))
+            }
+            // interpreters disagree on whether v0 or vx is added
+            JumpRegister(nnn) => Some(format!("computed jump to {:#05x} plus a register", nnn)),
+            _ => {
+                let condition = condition(stmt)?;
+                Some(format!("skip next if {}", condition))
+            }
+        }
+    }
+
+    fn render(&self) -> String {
+        let analysis = self.analysis;
+        let mut out = analysis.sprite_source();
+        if !analysis.sprites.is_empty() {
+            out.push('\n');
+        }
+
+        for line in &analysis.lines {
+            let address = line.address;
+
+            for construct in &self.constructs {
+                if let Construct::If { end, .. } = *construct {
+                    if end == address {
+                        let indent = "    ".repeat(self.depth(address) + 1);
+                        let _ = writeln!(out, "{}; }}", indent);
+                    }
+                }
+            }
+
+            if let Some(callers) = self.subroutines.get(&address) {
+                let callers: Vec<String> = callers.iter().map(|c| format!("{:#05x}", c)).collect();
+                let _ = writeln!(out, "\n; subroutine, called from {}", callers.join(", "));
+            }
+            if analysis.labels.contains(&address) {
+                let _ = writeln!(out, "{}:", self.name(address));
+            }
+            if self.loop_headers.contains(&address) {
+                let indent = "    ".repeat(self.depth(address));
+                let _ = writeln!(out, "{}; loop {{", indent);
+            }
+
+            let indent = "    ".repeat(self.depth(address) + 1);
+            let text = match analysis.symbolize(line, |target| self.name(target)) {
+                Some(stmt) if line.reachable => match self.annotation(line) {
+                    Some(note) => format!("{:<24}; {}", stmt.to_string(), note),
+                    None => stmt.to_string(),
+                },
+                Some(stmt) => format!("{:<24}; {:#05x} data", stmt.to_string(), address),
+                None => Analysis::data_word(line),
+            };
+            let _ = writeln!(out, "{}{}", indent, text.trim_end());
+        }
+
+        for construct in &self.constructs {
+            if let Construct::If { end, .. } = *construct {
+                if end == analysis.code_end {
+                    let indent = "    ".repeat(self.depth(end) + 1);
+                    let _ = writeln!(out, "{}; }}", indent);
+                }
+            }
+        }
+        if analysis.labels.contains(&analysis.code_end) {
+            let _ = writeln!(out, "{}:", self.name(analysis.code_end));
+        }
+
+        out
+    }
+}
+
+/// Decompiles `rom` into chirp source annotated with the structure of its
+/// control-flow graph: subroutines recovered from `call` targets, loops
+/// from backward jumps, and `if` blocks from a skip over a forward jump.
+///
+/// Structure only goes in comments and indentation, so the output still
+/// assembles back to the same rom, just like `disassemble`'s.
+pub fn decompile(rom: &[u8], entries: &[usize]) -> Disassembly {
+    let analysis = analyse(rom, entries);
+    let source = Decompiler::new(&analysis).render();
+
+    Disassembly {
+        source,
+        warnings: analysis.warnings,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::tests::{assemble, EVERY_OPCODE};
+
+    #[test]
+    fn round_trip() {
+        let rom = assemble(EVERY_OPCODE);
+        let decompiled = decompile(&rom, &[]);
+        assert_eq!(assemble(&decompiled.source), rom);
+    }
+
+    #[test]
+    fn structure() {
+        let rom = assemble(
+            "\
+    call draw
+loop:
+    add  v0,1
+    se   v0,10
+    jmp  skip
+    cls
+skip:
+    jmp  loop
+draw:
+    ret
+",
+        );
+        let decompiled = decompile(&rom, &[]);
+        assert_eq!(assemble(&decompiled.source), rom);
+        let source = decompiled.source;
+        assert!(source.contains("call sub_20c"));
+        assert!(source.contains("loop_202:\n    ; loop {"));
+        assert!(source.contains("; if v0 == 0x0a {"));
+        assert!(source.contains("; subroutine, called from 0x200"));
+    }
+
+    #[test]
+    fn jump_register() {
+        let rom = assemble("jmpr 0x300\n");
+        let source = decompile(&rom, &[]).source;
+        assert!(source.contains("; computed jump to 0x300 plus a register"));
+    }
+}
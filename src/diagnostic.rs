@@ -1,40 +1,463 @@
-use std::process::exit;
+use std::{
+    fmt::Write,
+    io::{stderr, IsTerminal},
+};
 
-use crate::lexer::Span;
+use crate::{json, lexer::Span, source_map::SourceMap};
 
-pub struct Diagnostic<'a> {
-    split_source: Vec<&'a str>,
+/// Stable diagnostic codes, so tools can match on them instead of messages.
+pub mod codes {
+    pub const SYNTAX: &str = "E0001";
+    pub const UNDECLARED_LABEL: &str = "E0002";
+    pub const UNDECLARED_SPRITE: &str = "E0003";
+    pub const INVALID_CHARACTER: &str = "E0004";
+    pub const MALFORMED_INTEGER: &str = "E0005";
+    pub const INTEGER_OUT_OF_RANGE: &str = "E0006";
+    pub const INVALID_REGISTER: &str = "E0007";
+    pub const OPERAND_OUT_OF_RANGE: &str = "E0008";
+    pub const ROM_TOO_LARGE: &str = "E0009";
+    pub const ADDRESS_OUT_OF_RANGE: &str = "E0010";
+    pub const UNSUPPORTED_INSTRUCTION: &str = "E0011";
+    pub const UNDECLARED_NAME: &str = "E0012";
+    pub const CIRCULAR_CONSTANT: &str = "E0013";
+    pub const NAME_CLASH: &str = "E0014";
+    pub const OVERFLOW: &str = "E0015";
+    pub const DIVIDE_BY_ZERO: &str = "E0016";
+
+    pub const DUPLICATE_LABEL: &str = "W0001";
+    pub const DUPLICATE_SPRITE: &str = "W0002";
+    pub const QUIRK: &str = "W0004";
+    pub const SKIPPED_LONG_LOAD: &str = "W0005";
+    pub const DUPLICATE_CONSTANT: &str = "W0006";
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A secondary span pointed at alongside the main one, e.g. the first
+/// declaration of a duplicated label.
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+/// A replacement for `span` that would fix the problem.
+#[derive(Debug, Clone)]
+pub struct Suggestion {
+    pub span: Span,
+    pub replacement: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: Option<&'static str>,
+    pub span: Span,
+    pub message: String,
+    pub labels: Vec<Label>,
+    pub suggestion: Option<Suggestion>,
+}
+
+impl Diagnostic {
+    pub fn error(span: Span, message: String) -> Self {
+        Self {
+            severity: Severity::Error,
+            code: None,
+            span,
+            message,
+            labels: vec![],
+            suggestion: None,
+        }
+    }
+
+    pub fn warning(span: Span, message: String) -> Self {
+        Self {
+            severity: Severity::Warning,
+            code: None,
+            span,
+            message,
+            labels: vec![],
+            suggestion: None,
+        }
+    }
+
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    pub fn with_label(mut self, span: Span, message: String) -> Self {
+        self.labels.push(Label { span, message });
+        self
+    }
+
+    pub fn with_suggestion(mut self, span: Span, replacement: String, message: String) -> Self {
+        self.suggestion = Some(Suggestion {
+            span,
+            replacement,
+            message,
+        });
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics.iter().any(Diagnostic::is_error)
 }
 
-impl<'a> Diagnostic<'a> {
-    pub fn from_source(source: &'a str) -> Self {
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+
+/// How a `Reporter` writes diagnostics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageFormat {
+    Human,
+    /// One JSON object per diagnostic, one per line, on stdout.
+    Json,
+}
+
+/// Renders diagnostics rustc-style: a header, the file position, and the
+/// offending source line with its span underlined.
+pub struct Reporter<'a> {
+    file_name: &'a str,
+    source_map: SourceMap<'a>,
+    color: bool,
+    format: MessageFormat,
+}
+
+impl<'a> Reporter<'a> {
+    pub fn new(file_name: &'a str, source: &'a str) -> Self {
         Self {
-            split_source: source.split_inclusive("\r\n").collect(),
+            file_name,
+            source_map: SourceMap::new(source),
+            color: stderr().is_terminal(),
+            format: MessageFormat::Human,
+        }
+    }
+
+    pub fn with_color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+
+    pub fn with_format(mut self, format: MessageFormat) -> Self {
+        self.format = format;
+        // the human rendering embedded in json goes to a tool, not a terminal
+        if format == MessageFormat::Json {
+            self.color = false;
+        }
+        self
+    }
+
+    fn paint(&self, style: &str, text: &str) -> String {
+        if self.color {
+            format!("{}{}{}", style, text, RESET)
+        } else {
+            text.to_string()
         }
     }
 
-    fn get_line(&self, lo: usize) -> usize {
-        let mut counter = 0;
+    pub fn render(&self, diagnostic: &Diagnostic) -> String {
+        let (severity, style) = match diagnostic.severity {
+            Severity::Warning => ("warning", YELLOW),
+            Severity::Error => ("error", RED),
+        };
+        let header = match diagnostic.code {
+            Some(code) => format!("{}[{}]", severity, code),
+            None => severity.to_string(),
+        };
+
+        let mut snippets = vec![(diagnostic.span, "", style, '^')];
+        for label in &diagnostic.labels {
+            snippets.push((label.span, label.message.as_str(), BLUE, '-'));
+        }
+
+        let gutter = snippets
+            .iter()
+            .map(|(span, ..)| self.source_map.position(span.lo).line)
+            .max()
+            .unwrap_or(1)
+            .to_string()
+            .len();
+        let bar = self.paint(BLUE, &format!("{:gutter$} |", ""));
+
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "{}{}",
+            self.paint(style, &header),
+            self.paint(BOLD, &format!(": {}", diagnostic.message))
+        );
 
-        for (i, line) in self.split_source.iter().enumerate() {
-            if (counter..counter + line.len()).contains(&lo) {
-                return i + 1;
+        for (i, (span, message, style, mark)) in snippets.into_iter().enumerate() {
+            let position = self.source_map.position(span.lo);
+            let arrow = if i == 0 { "-->" } else { ":::" };
+            let text = self.source_map.line_text(position.line);
+
+            // keep tabs so the underline lines up however the terminal renders them
+            let start = span.lo - self.source_map.line_start(position.line);
+            let start = start.min(text.len());
+            let padding: String = text[..start]
+                .chars()
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let end =
+                (span.hi - self.source_map.line_start(position.line)).clamp(start, text.len());
+            let width = text[start..end].chars().count().max(1);
+            let underline = mark.to_string().repeat(width);
+
+            if i > 0 {
+                let _ = writeln!(out, "{}", bar);
             }
+            let _ = writeln!(
+                out,
+                "{}{} {}:{}:{}",
+                " ".repeat(gutter),
+                self.paint(BLUE, arrow),
+                self.file_name,
+                position.line,
+                position.column
+            );
+            let _ = writeln!(out, "{}", bar);
+            let _ = writeln!(
+                out,
+                "{} {}",
+                self.paint(BLUE, &format!("{:>gutter$} |", position.line)),
+                text
+            );
+            let _ = writeln!(
+                out,
+                "{} {}{}",
+                bar,
+                padding,
+                self.paint(style, format!("{} {}", underline, message).trim_end())
+            );
+        }
+
+        if let Some(suggestion) = &diagnostic.suggestion {
+            let _ = writeln!(
+                out,
+                "{} {}: {}",
+                self.paint(BLUE, &format!("{:gutter$} =", "")),
+                self.paint(BOLD, "help"),
+                suggestion.message
+            );
+        }
+
+        out
+    }
+
+    fn span_json(&self, span: Span) -> String {
+        let (start, end) = self.source_map.span_positions(span);
+
+        json::Object::new()
+            .str("file", self.file_name)
+            .num("lo", span.lo)
+            .num("hi", span.hi)
+            .num("line_start", start.line)
+            .num("column_start", start.column)
+            .num("line_end", end.line)
+            .num("column_end", end.column)
+            .build()
+    }
+
+    pub fn render_json(&self, diagnostic: &Diagnostic) -> String {
+        let severity = match diagnostic.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        let code = match diagnostic.code {
+            Some(code) => json::string(code),
+            None => json::NULL.to_string(),
+        };
+        let labels = diagnostic.labels.iter().map(|label| {
+            json::Object::new()
+                .str("message", &label.message)
+                .field("span", self.span_json(label.span))
+                .build()
+        });
+        let suggestion = match &diagnostic.suggestion {
+            Some(suggestion) => json::Object::new()
+                .str("message", &suggestion.message)
+                .str("replacement", &suggestion.replacement)
+                .field("span", self.span_json(suggestion.span))
+                .build(),
+            None => json::NULL.to_string(),
+        };
 
-            counter += line.len();
+        json::Object::new()
+            .str("severity", severity)
+            .field("code", code)
+            .str("message", &diagnostic.message)
+            .str("file", self.file_name)
+            .field("span", self.span_json(diagnostic.span))
+            .field("labels", json::array(labels))
+            .field("suggestion", suggestion)
+            .str("rendered", &self.render(diagnostic))
+            .build()
+    }
+
+    pub fn report(&self, diagnostic: &Diagnostic) {
+        match self.format {
+            MessageFormat::Human => eprintln!("{}", self.render(diagnostic)),
+            MessageFormat::Json => println!("{}", self.render_json(diagnostic)),
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(lo: usize, hi: usize) -> Span {
+        Span { lo, hi }
+    }
 
-        0
+    fn render(source: &str, diagnostic: &Diagnostic) -> String {
+        Reporter::new("test.s", source)
+            .with_color(false)
+            .render(diagnostic)
     }
 
-    pub fn warn(&self, span: Span, message: String) {
-        let line_number = self.get_line(span.lo);
-        println!("warn at line {}: {}", line_number, message);
+    #[test]
+    fn snippet() {
+        let source = "cls\njmp nowhere\n";
+        let diagnostic = Diagnostic::error(span(8, 15), "label \"nowhere\" is not declared".into())
+            .with_code(codes::UNDECLARED_LABEL);
+        assert_eq!(
+            render(source, &diagnostic),
+            "\
+error[E0002]: label \"nowhere\" is not declared
+ --> test.s:2:5
+  |
+2 | jmp nowhere
+  |     ^^^^^^^
+"
+        );
     }
 
-    pub fn error(&self, span: Span, message: String) {
-        let line_number = self.get_line(span.lo);
-        println!("error at line {}: {}", line_number, message);
-        exit(0);
+    #[test]
+    fn multibyte() {
+        // a caret per character, lined up after the ones before it
+        let source = "; h\u{e9}llo\nmov \u{e9}\u{e9}, v1\n";
+        let diagnostic = Diagnostic::error(span(13, 17), "invalid character".into());
+        assert_eq!(
+            render(source, &diagnostic),
+            "\
+error: invalid character
+ --> test.s:2:5
+  |
+2 | mov \u{e9}\u{e9}, v1
+  |     ^^
+"
+        );
+    }
+
+    #[test]
+    fn secondary_labels() {
+        let diagnostic = Diagnostic::warning(span(3, 5), "label \"a\" is already declared".into())
+            .with_code(codes::DUPLICATE_LABEL)
+            .with_label(span(0, 2), "first declared here".into());
+        assert_eq!(
+            render("a:\na:\n", &diagnostic),
+            "\
+warning[W0001]: label \"a\" is already declared
+ --> test.s:2:1
+  |
+2 | a:
+  | ^^
+  |
+ ::: test.s:1:1
+  |
+1 | a:
+  | -- first declared here
+"
+        );
+    }
+
+    #[test]
+    fn end_of_file() {
+        let diagnostic =
+            Diagnostic::error(span(11, 11), "expected register, found end of line".into());
+        assert_eq!(
+            render("cls\nmov v0,", &diagnostic),
+            "\
+error: expected register, found end of line
+ --> test.s:2:8
+  |
+2 | mov v0,
+  |        ^
+"
+        );
+    }
+
+    #[test]
+    fn suggestion() {
+        let diagnostic = Diagnostic::error(span(4, 8), "unknown mnemonic".into()).with_suggestion(
+            span(4, 8),
+            "cls".into(),
+            "did you mean `cls`?".into(),
+        );
+        assert_eq!(
+            render("ret\nclrs\n", &diagnostic),
+            "\
+error: unknown mnemonic
+ --> test.s:2:1
+  |
+2 | clrs
+  | ^^^^
+  = help: did you mean `cls`?
+"
+        );
+    }
+
+    #[test]
+    fn json() {
+        let diagnostic = Diagnostic::warning(span(3, 5), "label \"a\" is already declared".into())
+            .with_code(codes::DUPLICATE_LABEL)
+            .with_label(span(0, 2), "first declared here".into())
+            .with_suggestion(span(3, 5), "b:".into(), "rename it".into());
+        let reporter = Reporter::new("dir\\test.s", "a:\na:\n");
+        let span = |lo, hi, line| {
+            format!(
+                r#"{{"file":"dir\\test.s","lo":{},"hi":{},"line_start":{},"column_start":1,"line_end":{},"column_end":3}}"#,
+                lo, hi, line, line
+            )
+        };
+        let expected = [
+            r#"{"severity":"warning","code":"W0001","#.to_string(),
+            r#""message":"label \"a\" is already declared","file":"dir\\test.s","#.to_string(),
+            format!(r#""span":{},"#, span(3, 5, 2)),
+            format!(
+                r#""labels":[{{"message":"first declared here","span":{}}}],"#,
+                span(0, 2, 1)
+            ),
+            format!(
+                r#""suggestion":{{"message":"rename it","replacement":"b:","span":{}}},"#,
+                span(3, 5, 2)
+            ),
+            r#""rendered":"warning[W0001]: label \"a\" is already declared\n --> dir\\test.s:2:1\n"#
+                .to_string(),
+            r#"  |\n2 | a:\n  | ^^\n  |\n ::: dir\\test.s:1:1\n  |\n1 | a:\n"#.to_string(),
+            r#"  | -- first declared here\n  = help: rename it\n"}"#.to_string(),
+        ];
+        assert_eq!(reporter.render_json(&diagnostic), expected.concat());
+
+        let bare = Diagnostic::error(Span { lo: 0, hi: 1 }, "oops".into());
+        let json = reporter.render_json(&bare);
+        assert!(json.contains(r#""code":null"#));
+        assert!(json.contains(r#""labels":[],"suggestion":null"#));
     }
 }
@@ -0,0 +1,633 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+};
+
+use crate::{
+    interp::PROGRAM_START,
+    parser::Stmt_::{self, *},
+    target::Target,
+    validate,
+};
+
+/// Decodes `opcode` into the statement `interp` would have encoded it from.
+///
+/// Only canonical encodings decode: `8xy6` with a non-zero `y`, for example,
+/// returns `None` because `shr vx` always assembles to `8x06`.
+pub fn decode(opcode: u16) -> Option<Stmt_> {
+    let x = ((opcode & 0x0F00) >> 8) as u8;
+    let y = ((opcode & 0x00F0) >> 4) as u8;
+    let n = opcode & 0x000F;
+    let nn = opcode & 0x00FF;
+    let nnn = opcode & 0x0FFF;
+
+    let stmt = match (opcode & 0xF000) >> 12 {
+        0x0 => match opcode {
+            0x0000 => Nop,
+            0x00E0 => Clear,
+            0x00EE => Return,
+            0x00C0..=0x00CF => ScrollDown(n),
+            0x00D0..=0x00DF => ScrollUp(n),
+            0x00FB => ScrollRight,
+            0x00FC => ScrollLeft,
+            0x00FD => Exit,
+            0x00FE => LowRes,
+            0x00FF => HighRes,
+            _ => return None,
+        },
+        0x1 => JumpInteger(nnn),
+        0x2 => CallInteger(nnn),
+        0x3 => SkipEqualsInteger(x, nn),
+        0x4 => SkipNotEqualsInteger(x, nn),
+        0x5 => match n {
+            0x0 => SkipEqualsRegister(x, y),
+            0x2 => SaveRange(x, y),
+            0x3 => LoadRange(x, y),
+            _ => return None,
+        },
+        0x6 => MoveRegisterInteger(x, nn),
+        0x7 => AddRegisterInteger(x, nn),
+        0x8 => match n {
+            0x0 => MoveRegisterRegister(x, y),
+            0x1 => Or(x, y),
+            0x2 => And(x, y),
+            0x3 => Xor(x, y),
+            0x4 => AddRegisterRegister(x, y),
+            0x5 => Subtract(x, y),
+            0x6 if y == 0 => ShiftRight(x),
+            0x7 => SubtractReverse(x, y),
+            0xE if y == 0 => ShiftLeft(x),
+            _ => return None,
+        },
+        0x9 if n == 0 => SkipNotEqualsRegister(x, y),
+        0xA => MoveIRegisterInteger(nnn),
+        0xB => JumpRegister(nnn),
+        0xC => Random(x, nn),
+        0xD => Draw(x, y, n),
+        0xE => match nn {
+            0x9E => SkipKeyPressed(x),
+            0xA1 => SkipKeyNotPressed(x),
+            _ => return None,
+        },
+        0xF => match nn {
+            0x01 => Plane(x as u16),
+            0x02 if x == 0 => Audio,
+            0x07 => MoveRegisterDelay(x),
+            0x0A => WaitKeyPress(x),
+            0x15 => MoveDelayRegister(x),
+            0x18 => MoveSoundRegister(x),
+            0x1E => AddIRegisterRegister(x),
+            0x29 => Sprite(x),
+            0x33 => Bcd(x),
+            0x55 => Save(x),
+            0x65 => Load(x),
+            0x30 => BigSprite(x),
+            0x75 => SaveFlags(x),
+            0x85 => LoadFlags(x),
+            0x3A => Pitch(x),
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    Some(stmt)
+}
+
+/// Decodes the instruction at `offset` in `rom`, reading the word after
+/// it too for the 4-byte `mov i,long`.
+pub fn decode_at(rom: &[u8], offset: usize) -> Option<Stmt_> {
+    let word = |offset: usize| {
+        let bytes = rom.get(offset..offset + 2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+
+    match word(offset)? {
+        0xF000 => Some(MoveIRegisterLong(word(offset + 2)?)),
+        opcode => decode(opcode),
+    }
+}
+
+/// Whether `opcode` is a CHIP-8 instruction `decode` turns down because
+/// chirp can't write it: the shifts with a `vy`, which chirp writes as 0.
+/// Control carries on past them.
+fn is_unwritable_instruction(opcode: u16) -> bool {
+    matches!(opcode & 0xF00F, 0x8006 | 0x800E)
+}
+
+fn label_name(address: usize) -> String {
+    format!("label_{:03x}", address)
+}
+
+fn sprite_name(address: usize) -> String {
+    format!("sprite_{:03x}", address)
+}
+
+/// Where control can go after executing the instruction at `address`.
+/// `next` is the instruction after it, which skips jump over whole, as
+/// XO-CHIP does for `mov i,long`.
+pub fn successors(address: usize, stmt: &Stmt_, next: Option<&Stmt_>) -> Vec<usize> {
+    let after = address + stmt.size();
+    match *stmt {
+        JumpInteger(nnn) => vec![nnn as usize],
+        CallInteger(nnn) => vec![nnn as usize, after],
+        Return | JumpRegister(_) | Exit => vec![],
+        SkipEqualsInteger(..)
+        | SkipNotEqualsInteger(..)
+        | SkipEqualsRegister(..)
+        | SkipNotEqualsRegister(..)
+        | SkipKeyPressed(_)
+        | SkipKeyNotPressed(_) => vec![after, after + next.map_or(2, Stmt_::size)],
+        _ => vec![after],
+    }
+}
+
+pub struct Disassembly {
+    pub source: String,
+    /// Things the disassembler could not work out, by address.
+    pub warnings: Vec<String>,
+}
+
+/// Reachable instructions by address, following control flow from every
+/// entry point the way the encoder in `interp` lays instructions out.
+pub fn trace(rom: &[u8], entries: &[usize], warnings: &mut Vec<String>) -> BTreeMap<usize, Stmt_> {
+    let end = PROGRAM_START + rom.len();
+    let mut code = BTreeMap::new();
+    let mut pending: Vec<usize> = entries.to_vec();
+
+    while let Some(address) = pending.pop() {
+        if code.contains_key(&address) {
+            continue;
+        }
+        if !(PROGRAM_START..end - 1).contains(&address) {
+            warnings.push(format!("control reaches {:#05x}, outside the rom", address));
+            continue;
+        }
+        if !(address - PROGRAM_START).is_multiple_of(2) {
+            warnings.push(format!(
+                "control reaches odd address {:#05x}, which chirp cannot place code at",
+                address
+            ));
+            continue;
+        }
+
+        let offset = address - PROGRAM_START;
+        let opcode = u16::from_be_bytes([rom[offset], rom[offset + 1]]);
+        let stmt = match decode_at(rom, offset) {
+            Some(stmt) => stmt,
+            None if is_unwritable_instruction(opcode) => {
+                warnings.push(format!(
+                    "{:#06x} at {:#05x} shifts vy, which chirp can't write, so it is kept \
+                     as `dw {:#06x}`",
+                    opcode, address, opcode
+                ));
+                Word(opcode)
+            }
+            None => {
+                warnings.push(format!(
+                    "control reaches {:#06x} at {:#05x}, which is not an instruction",
+                    opcode, address
+                ));
+                continue;
+            }
+        };
+
+        if let JumpRegister(nnn) = stmt {
+            warnings.push(format!(
+                "jmpr {:#05x} at {:#05x} jumps to a computed address, \
+                 pass its targets with --entry to disassemble them",
+                nnn, address
+            ));
+        }
+
+        let next = decode_at(rom, offset + stmt.size());
+        pending.extend(successors(address, &stmt, next.as_ref()));
+        code.insert(address, stmt);
+    }
+
+    code
+}
+
+/// One word of the code region.
+pub struct Line {
+    pub address: usize,
+    pub opcode: u16,
+    /// `None` when the word doesn't decode.
+    pub stmt: Option<Stmt_>,
+    pub reachable: bool,
+}
+
+/// A rom split into code and sprite data, with every address that code
+/// refers to.
+pub struct Analysis {
+    /// Every word from 0x200 up to the end of the last reachable
+    /// instruction, reachable or not.
+    pub lines: Vec<Line>,
+    /// Code addresses that are jumped to, called, or given as entry points.
+    pub labels: BTreeSet<usize>,
+    /// Sprite data after the code, split wherever `mov i` points into it.
+    pub sprites: Vec<(usize, Vec<u8>)>,
+    pub code_end: usize,
+    pub warnings: Vec<String>,
+}
+
+/// Traces control flow from 0x200 and any extra `entries`, so only
+/// reachable words become code. `interp` always places sprites after the
+/// code, so unreachable bytes past the last instruction become sprite data;
+/// unreachable bytes between instructions stay inline.
+pub fn analyse(rom: &[u8], entries: &[usize]) -> Analysis {
+    let mut warnings = vec![];
+    let mut entries = entries.to_vec();
+    entries.insert(0, PROGRAM_START);
+
+    let code = trace(rom, &entries, &mut warnings);
+    let code_end = code
+        .iter()
+        .next_back()
+        .map_or(PROGRAM_START, |(address, stmt)| address + stmt.size());
+    let end = PROGRAM_START + rom.len();
+
+    // extra entry points are there because something jumps to them
+    let mut labels: BTreeSet<usize> = entries[1..]
+        .iter()
+        .copied()
+        .filter(|entry| code.contains_key(entry))
+        .collect();
+    let mut sprites = BTreeSet::new();
+    if code_end < end {
+        sprites.insert(code_end);
+    }
+
+    let mut lines = vec![];
+    let mut address = PROGRAM_START;
+    while address < code_end {
+        let offset = address - PROGRAM_START;
+        let opcode = u16::from_be_bytes([rom[offset], rom[offset + 1]]);
+        let reachable = code.contains_key(&address);
+        // a `mov i,long` can't swallow a word that is reached on its own
+        let decoded = decode_at(rom, offset)
+            .filter(|stmt| stmt.size() == 2 || !code.contains_key(&(address + 2)));
+        // chirp rejects writes to v15, so instructions making them can't be
+        // written back
+        let stmt = decoded.clone().filter(|stmt| !writes_flag_register(stmt));
+        let size = stmt.as_ref().map_or(2, Stmt_::size);
+
+        match stmt {
+            Some(JumpInteger(nnn) | CallInteger(nnn)) if reachable && is_code(nnn, code_end) => {
+                labels.insert(nnn as usize);
+            }
+            Some(MoveIRegisterInteger(nnn) | MoveIRegisterLong(nnn))
+                if reachable && (code_end..end).contains(&(nnn as usize)) =>
+            {
+                sprites.insert(nnn as usize);
+            }
+            None => {
+                if let Some(node) = decoded {
+                    warnings.push(format!(
+                        "`{}` at {:#05x} writes to v15, which chirp rejects, so it is kept \
+                         as `dw {:#06x}`",
+                        node, address, opcode
+                    ));
+                }
+            }
+            _ => {}
+        }
+
+        lines.push(Line {
+            address,
+            opcode,
+            stmt,
+            reachable,
+        });
+        address += size;
+    }
+
+    let target = lines
+        .iter()
+        .filter_map(|line| line.stmt.as_ref())
+        .map(Target::introducing)
+        .max()
+        .unwrap_or_default();
+    if target != Target::default() {
+        warnings.push(format!(
+            "the rom uses instructions from {}, reassemble it with --target {}",
+            target, target
+        ));
+    }
+
+    let bounds: Vec<usize> = sprites.iter().copied().chain([end]).collect();
+    let sprites = bounds
+        .windows(2)
+        .map(|range| {
+            let data = rom[range[0] - PROGRAM_START..range[1] - PROGRAM_START].to_vec();
+            (range[0], data)
+        })
+        .collect();
+
+    Analysis {
+        lines,
+        labels,
+        sprites,
+        code_end,
+        warnings,
+    }
+}
+
+fn writes_flag_register(stmt: &Stmt_) -> bool {
+    matches!(validate::destination(stmt), Some((_, 0xF)))
+}
+
+fn is_code(address: u16, code_end: usize) -> bool {
+    let address = address as usize;
+    (PROGRAM_START..=code_end).contains(&address) && (address - PROGRAM_START).is_multiple_of(2)
+}
+
+impl Analysis {
+    /// Sprite declarations, which must come before any code that uses them
+    /// and whose order decides where they land.
+    pub fn sprite_source(&self) -> String {
+        let mut out = String::new();
+        for (address, data) in &self.sprites {
+            let data = data.iter().map(|byte| *byte as u16).collect();
+            let _ = writeln!(out, "{}", DeclareSprite(sprite_name(*address), data));
+        }
+        out
+    }
+
+    /// `line`'s statement with code and sprite addresses replaced by names,
+    /// `label` naming the code addresses.
+    pub fn symbolize(&self, line: &Line, label: impl Fn(usize) -> String) -> Option<Stmt_> {
+        let is_sprite = |nnn: u16| {
+            self.sprites
+                .iter()
+                .any(|(address, _)| *address == nnn as usize)
+        };
+
+        let stmt = match line.stmt.clone()? {
+            JumpInteger(nnn) if line.reachable && is_code(nnn, self.code_end) => {
+                JumpLabel(label(nnn as usize))
+            }
+            CallInteger(nnn) if line.reachable && is_code(nnn, self.code_end) => {
+                CallLabel(label(nnn as usize))
+            }
+            MoveIRegisterInteger(nnn) if line.reachable && is_sprite(nnn) => {
+                MoveIRegisterSprite(sprite_name(nnn as usize))
+            }
+            MoveIRegisterLong(nnnn) if line.reachable && is_sprite(nnnn) => {
+                MoveIRegisterLongSprite(sprite_name(nnnn as usize))
+            }
+            stmt => stmt,
+        };
+        Some(stmt)
+    }
+
+    /// An undecodable `line` as a `dw` of its raw word, noting the
+    /// instruction it is when chirp just can't write it, and marking it as
+    /// data when nothing reaches it.
+    pub fn data_word(line: &Line) -> String {
+        let note = match decode(line.opcode) {
+            Some(stmt) => format!(" {}", stmt),
+            None if line.reachable => String::new(),
+            None => " data".to_string(),
+        };
+        format!(
+            "{:<24}; {:#05x}{}",
+            Word(line.opcode).to_string(),
+            line.address,
+            note
+        )
+    }
+}
+
+/// Disassembles `rom` into chirp source that assembles back to the same
+/// bytes. See `analyse` for how code and data are told apart; unreachable
+/// words between instructions are marked `; data`, and written with `dw`
+/// when they don't decode.
+pub fn disassemble(rom: &[u8], entries: &[usize]) -> Disassembly {
+    let analysis = analyse(rom, entries);
+    let mut out = analysis.sprite_source();
+    if !analysis.sprites.is_empty() {
+        out.push('\n');
+    }
+
+    for line in &analysis.lines {
+        if analysis.labels.contains(&line.address) {
+            let _ = writeln!(out, "{}:", label_name(line.address));
+        }
+
+        let _ = match analysis.symbolize(line, label_name) {
+            Some(stmt) if line.reachable => {
+                writeln!(out, "    {:<24}; {:#05x}", stmt.to_string(), line.address)
+            }
+            Some(stmt) => writeln!(
+                out,
+                "    {:<24}; {:#05x} data",
+                stmt.to_string(),
+                line.address
+            ),
+            None => writeln!(out, "    {}", Analysis::data_word(line)),
+        };
+    }
+    if analysis.labels.contains(&analysis.code_end) {
+        let _ = writeln!(out, "{}:", label_name(analysis.code_end));
+    }
+
+    Disassembly {
+        source: out,
+        warnings: analysis.warnings,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{assemble_with, Options};
+
+    /// Every instruction in the README, with sprites and labels jumped to
+    /// both forwards and backwards.
+    pub(crate) const EVERY_OPCODE: &str = "\
+$player 0x13 0x83 0xad 0xa1 0xd3
+$tiles 0xff 0x81 0xff
+    cls
+    call draw
+    se   v0,0x12
+    sne  v1,0x34
+    se   v2,v3
+    mov  v4,0x56
+    add  v5,0x78
+    mov  v6,v7
+    or   v8,v9
+    and  v10,v11
+    xor  v12,v13
+    add  v14,v0
+    sub  v1,v2
+    shr  v3
+    subn v4,v5
+    shl  v6
+    sne  v7,v8
+    mov  i,0x123
+    rnd  v9,0xab
+    skp  v10
+    sknp v11
+    mov  v12,dt
+    wait v13
+    mov  dt,v14
+    mov  st,v0
+    add  i,v1
+    spr  v2
+    bcd  v3
+    save v4
+    load v5
+    scd  3
+    scr
+    scl
+    low
+    high
+    hspr v6
+    savef v7
+    loadf v2
+    scu  4
+    save v1-v3
+    load v3-v1
+    plane 2
+    audio
+    pitch v4
+    mov  i,long tiles
+loop:
+    add  v0,1
+    sne  v0,10
+    jmp  done
+    jmp  loop
+done:
+    exit
+    jmpr 0x300
+    nop
+    dw   0xffff
+draw:
+    mov  i,player
+    drw  v0,v1,5
+    drw  v0,v1,0
+    ret
+";
+
+    pub(crate) fn assemble(source: &str) -> Vec<u8> {
+        let options = Options {
+            target: Target::XoChip,
+            ..Options::default()
+        };
+        match assemble_with(source, &options) {
+            Ok(assembly) => assembly.rom,
+            Err(diagnostics) => panic!("{:?}\n{}", diagnostics, source),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let rom = assemble(EVERY_OPCODE);
+        let disassembly = disassemble(&rom, &[]);
+        assert_eq!(assemble(&disassembly.source), rom);
+    }
+
+    #[test]
+    fn labels_and_sprites() {
+        let rom = assemble(EVERY_OPCODE);
+        let source = disassemble(&rom, &[]).source;
+        assert!(source.contains("call label_"));
+        assert!(source.contains("jmp  label_"));
+        assert!(source.contains("mov  i,sprite_"));
+        assert!(source.contains("mov  i,long sprite_"));
+        assert!(source.contains("$sprite_"));
+    }
+
+    #[test]
+    fn sprite_after_jump_is_data() {
+        // the sprite decodes as `mov v0,0x12`, but nothing reaches it
+        let rom = assemble(
+            "\
+$ball 0x60 0x12
+loop:
+    mov  i,ball
+    drw  v0,v1,2
+    jmp  loop
+",
+        );
+        let mut warnings = vec![];
+        let code = trace(&rom, &[PROGRAM_START], &mut warnings);
+        assert_eq!(
+            code.keys().copied().collect::<Vec<_>>(),
+            [0x200, 0x202, 0x204]
+        );
+        assert!(warnings.is_empty());
+
+        let analysis = analyse(&rom, &[]);
+        assert_eq!(analysis.code_end, 0x206);
+        assert_eq!(analysis.sprites, [(0x206, vec![0x60, 0x12])]);
+
+        let disassembly = disassemble(&rom, &[]);
+        assert!(disassembly.source.contains("$sprite_206 0x60 0x12"));
+        assert_eq!(assemble(&disassembly.source), rom);
+    }
+
+    #[test]
+    fn shift_with_vy() {
+        // `mov v0,3`, `shr v0,v1`, `mov v1,5` then `jmp 0x200`
+        let rom = [0x60, 0x03, 0x80, 0x16, 0x61, 0x05, 0x12, 0x00];
+        let mut warnings = vec![];
+        let code = trace(&rom, &[PROGRAM_START], &mut warnings);
+        assert_eq!(
+            code.keys().copied().collect::<Vec<_>>(),
+            [0x200, 0x202, 0x204, 0x206]
+        );
+        assert_eq!(code[&0x202], Word(0x8016));
+        assert!(warnings[0].contains("0x8016 at 0x202 shifts vy"));
+
+        let disassembly = disassemble(&rom, &[]);
+        assert!(disassembly
+            .source
+            .contains("dw   0x8016             ; 0x202\n"));
+        assert!(disassembly
+            .source
+            .contains("mov  v1,0x05            ; 0x204"));
+        assert_eq!(assemble(&disassembly.source), rom);
+    }
+
+    #[test]
+    fn unresolved_jump_register() {
+        let rom = assemble("jmpr 0x204\ncls\nret\n");
+        let mut warnings = vec![];
+        let code = trace(&rom, &[PROGRAM_START], &mut warnings);
+        assert_eq!(code.len(), 1);
+        assert!(warnings[0].contains("jmpr 0x204"));
+
+        let code = trace(&rom, &[PROGRAM_START, 0x204], &mut warnings);
+        assert_eq!(code.keys().copied().collect::<Vec<_>>(), [0x200, 0x204]);
+    }
+
+    #[test]
+    fn flag_register() {
+        // `add v15,v1`, `se v15,1` then `jmp 0x200`
+        let rom = [0x8F, 0x14, 0x3F, 0x01, 0x12, 0x00];
+        let disassembly = disassemble(&rom, &[]);
+        assert!(disassembly
+            .source
+            .contains("dw   0x8f14             ; 0x200 add  v15,v1"));
+        assert!(disassembly.source.contains("se   v15,0x01"));
+        assert!(disassembly
+            .warnings
+            .iter()
+            .any(|warning| warning.contains("`add  v15,v1` at 0x200 writes to v15")));
+        assert!(assemble_with("add v15,v1", &Options::default()).is_err());
+        assert_eq!(assemble(&disassembly.source), rom);
+    }
+
+    #[test]
+    fn raw_words() {
+        // `jmp 0x204` over 0xffff, then `cls`, `jmp 0x204` and a sprite
+        let rom = [0x12, 0x04, 0xFF, 0xFF, 0x00, 0xE0, 0x12, 0x04, 0xF0, 0x90];
+        let disassembly = disassemble(&rom, &[]);
+        assert!(disassembly
+            .source
+            .contains("dw   0xffff             ; 0x202 data"));
+        assert!(disassembly.warnings.is_empty());
+        assert_eq!(assemble(&disassembly.source), rom);
+    }
+}
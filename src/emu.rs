@@ -0,0 +1,929 @@
+//! A headless CHIP-8 machine for running what `assemble` produces.
+
+use std::fmt;
+
+use crate::{
+    disasm,
+    quirks::{MemoryIncrement, Profile, Quirks},
+    target::Target,
+};
+
+pub use crate::MEMORY_SIZE;
+
+/// The classic display; some targets differ, see `Target::resolution`.
+pub const WIDTH: usize = 64;
+pub const HEIGHT: usize = 32;
+/// The SUPER-CHIP high resolution mode doubles both sides.
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
+pub const STACK_SIZE: usize = 16;
+/// Where the built-in hex font lives, 5 bytes per digit.
+pub const FONT_START: usize = 0x050;
+/// Where the SUPER-CHIP big hex font lives, 10 bytes per digit.
+pub const BIG_FONT_START: usize = 0x0a0;
+/// What `pitch` starts at, which plays the audio pattern at 4000 Hz.
+pub const DEFAULT_PITCH: u8 = 64;
+/// The classic interpreters ran at roughly this many instructions per frame.
+pub const DEFAULT_SPEED: usize = 11;
+
+#[rustfmt::skip]
+const FONT: [u8; 80] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // a
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // b
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // c
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // d
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // e
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // f
+];
+
+#[rustfmt::skip]
+const BIG_FONT: [u8; 160] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+    0x3E, 0x7C, 0xE0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+    0x18, 0x3C, 0x66, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // a
+    0xFC, 0xFE, 0xC3, 0xC3, 0xFE, 0xFE, 0xC3, 0xC3, 0xFE, 0xFC, // b
+    0x3C, 0x7E, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0x7E, 0x3C, // c
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // d
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFC, 0xC0, 0xC0, 0xFF, 0xFF, // e
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFC, 0xC0, 0xC0, 0xC0, 0xC0, // f
+];
+
+/// Why the machine stopped. `pc` is where the offending instruction is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    RomTooLarge {
+        len: usize,
+        available: usize,
+    },
+    InvalidOpcode {
+        pc: u16,
+        opcode: u16,
+    },
+    StackOverflow {
+        pc: u16,
+    },
+    StackUnderflow {
+        pc: u16,
+    },
+    OutOfMemory {
+        pc: u16,
+        address: usize,
+    },
+    /// The program ran `exit`.
+    Exited {
+        pc: u16,
+    },
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Fault::RomTooLarge { len, available } => write!(
+                f,
+                "rom is {} bytes but only {} fit in memory",
+                len, available
+            ),
+            Fault::InvalidOpcode { pc, opcode } => {
+                write!(f, "invalid opcode {:04x} at {:#05x}", opcode, pc)
+            }
+            Fault::StackOverflow { pc } => write!(f, "stack overflow at {:#05x}", pc),
+            Fault::StackUnderflow { pc } => {
+                write!(f, "ret with an empty stack at {:#05x}", pc)
+            }
+            Fault::OutOfMemory { pc, address } => {
+                write!(f, "access to {:#x} outside memory at {:#05x}", address, pc)
+            }
+            Fault::Exited { pc } => write!(f, "program exited at {:#05x}", pc),
+        }
+    }
+}
+
+pub struct Machine {
+    /// `MEMORY_SIZE` bytes, or 64 KiB for XO-CHIP.
+    pub memory: Vec<u8>,
+    pub v: [u8; 16],
+    pub i: u16,
+    pub pc: u16,
+    pub stack: Vec<u16>,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    /// Big enough for high resolution; only the top left `width()` by
+    /// `height()` is in use. Each pixel has a bit per XO-CHIP plane, so
+    /// it is 0 when off and 1 when lit on a single plane program.
+    pub display: [[u8; HIRES_WIDTH]; HIRES_HEIGHT],
+    /// The planes `plane` selected for drawing, clearing and scrolling.
+    pub planes: u8,
+    /// Whether `high` switched to 128x64.
+    pub hires: bool,
+    /// Set whenever the display changes, for frontends to clear once drawn.
+    pub display_changed: bool,
+    pub keys: [bool; 16],
+    /// What `savef` stored. Like the HP-48 flags they stand for, these
+    /// survive `reset`.
+    pub flags: [u8; 8],
+    /// The 16 bytes of 1-bit samples `audio` loaded, played at `pitch`.
+    pub pattern: [u8; 16],
+    pub pitch: u8,
+    /// Instructions run by each call to `frame`.
+    pub speed: usize,
+    pub quirks: Quirks,
+    /// Whether a `drw` ran this frame, for the display wait quirk.
+    drawn: bool,
+    /// The key a `wait` saw go down, until it comes back up.
+    waiting_key: Option<u8>,
+    target: Target,
+    rom: Vec<u8>,
+    rng: u64,
+}
+
+impl Machine {
+    pub fn new(rom: &[u8]) -> Result<Self, Fault> {
+        Self::for_target(rom, Target::default())
+    }
+
+    /// A machine laid out like `target`, which only runs the instructions
+    /// `target` has.
+    pub fn for_target(rom: &[u8], target: Target) -> Result<Self, Fault> {
+        let available = target.memory_size() - target.load_address();
+        if rom.len() > available {
+            return Err(Fault::RomTooLarge {
+                len: rom.len(),
+                available,
+            });
+        }
+
+        let mut machine = Self {
+            memory: vec![0; target.memory_size()],
+            v: [0; 16],
+            i: 0,
+            pc: target.entry() as u16,
+            stack: Vec::with_capacity(STACK_SIZE),
+            delay_timer: 0,
+            sound_timer: 0,
+            display: [[0; HIRES_WIDTH]; HIRES_HEIGHT],
+            planes: 1,
+            hires: false,
+            display_changed: true,
+            keys: [false; 16],
+            flags: [0; 8],
+            pattern: [0; 16],
+            pitch: DEFAULT_PITCH,
+            speed: DEFAULT_SPEED,
+            quirks: Profile::default().quirks(),
+            drawn: false,
+            waiting_key: None,
+            target,
+            rom: rom.to_vec(),
+            rng: 0x2545_f491_4f6c_dd1d,
+        };
+        machine.reset();
+        Ok(machine)
+    }
+
+    /// Seeds `rnd`, which is otherwise deterministic from run to run.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = seed.max(1);
+        self
+    }
+
+    pub fn with_quirks(mut self, quirks: Quirks) -> Self {
+        self.quirks = quirks;
+        self
+    }
+
+    pub fn target(&self) -> Target {
+        self.target
+    }
+
+    /// Puts the machine back the way `new` left it, keeping the seed,
+    /// speed and quirks.
+    pub fn reset(&mut self) {
+        let load = self.target.load_address();
+        self.memory.fill(0);
+        self.memory[FONT_START..FONT_START + FONT.len()].copy_from_slice(&FONT);
+        self.memory[BIG_FONT_START..BIG_FONT_START + BIG_FONT.len()].copy_from_slice(&BIG_FONT);
+        self.memory[load..load + self.rom.len()].copy_from_slice(&self.rom);
+        self.v = [0; 16];
+        self.i = 0;
+        self.pc = self.target.entry() as u16;
+        self.stack.clear();
+        self.delay_timer = 0;
+        self.sound_timer = 0;
+        self.display = [[0; HIRES_WIDTH]; HIRES_HEIGHT];
+        self.planes = 1;
+        self.hires = false;
+        self.display_changed = true;
+        self.keys = [false; 16];
+        self.pattern = [0; 16];
+        self.pitch = DEFAULT_PITCH;
+        self.drawn = false;
+        self.waiting_key = None;
+    }
+
+    pub fn set_key(&mut self, key: u8, pressed: bool) {
+        self.keys[(key & 0xF) as usize] = pressed;
+    }
+
+    pub fn sound_playing(&self) -> bool {
+        self.sound_timer > 0
+    }
+
+    /// The width of the display in the current resolution.
+    pub fn width(&self) -> usize {
+        if self.hires {
+            HIRES_WIDTH
+        } else {
+            self.target.resolution().0
+        }
+    }
+
+    /// The height of the display in the current resolution.
+    pub fn height(&self) -> usize {
+        if self.hires {
+            HIRES_HEIGHT
+        } else {
+            self.target.resolution().1
+        }
+    }
+
+    /// The opcode at `pc`.
+    pub fn opcode(&self) -> u16 {
+        let pc = self.pc as usize % self.memory.len();
+        u16::from_be_bytes([self.memory[pc], self.memory[(pc + 1) % self.memory.len()]])
+    }
+
+    /// Runs `speed` instructions, then ticks the timers once, as a 60 Hz
+    /// frame would. With the display wait quirk the frame ends early after
+    /// a `drw`.
+    pub fn frame(&mut self) -> Result<(), Fault> {
+        self.drawn = false;
+        for _ in 0..self.speed {
+            self.step()?;
+            if self.drawn && self.quirks.display_wait {
+                break;
+            }
+        }
+
+        self.tick_timers();
+        Ok(())
+    }
+
+    /// Counts both timers down by one 60 Hz tick. `frame` does this itself;
+    /// callers driving `step` directly call it every `speed` instructions.
+    pub fn tick_timers(&mut self) {
+        self.delay_timer = self.delay_timer.saturating_sub(1);
+        self.sound_timer = self.sound_timer.saturating_sub(1);
+    }
+
+    fn random(&mut self) -> u8 {
+        // xorshift64, plenty for games
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 32) as u8
+    }
+
+    fn memory_index(&self, pc: u16, address: usize) -> Result<usize, Fault> {
+        if address < self.memory.len() {
+            Ok(address)
+        } else {
+            Err(Fault::OutOfMemory { pc, address })
+        }
+    }
+
+    /// `address` wrapped around the end of memory.
+    pub fn wrap(&self, address: usize) -> u16 {
+        (address % self.memory.len()) as u16
+    }
+
+    /// Runs the instruction at `pc`.
+    pub fn step(&mut self) -> Result<(), Fault> {
+        let pc = self.pc;
+        let opcode = self.opcode();
+        let x = ((opcode & 0x0F00) >> 8) as usize;
+        let y = ((opcode & 0x00F0) >> 4) as usize;
+        let n = (opcode & 0x000F) as u8;
+        let nn = (opcode & 0x00FF) as u8;
+        let nnn = opcode & 0x0FFF;
+        let invalid = Fault::InvalidOpcode { pc, opcode };
+        if disasm::decode_at(&self.memory, pc as usize)
+            .is_some_and(|stmt| !self.target.supports(&stmt))
+        {
+            return Err(invalid);
+        }
+
+        self.pc = self.wrap(pc as usize + 2);
+
+        match opcode & 0xF000 {
+            0x0000 => match opcode {
+                0x0000 => {}
+                0x00C0..=0x00CF => self.scroll(0, n as isize),
+                0x00D0..=0x00DF => self.scroll(0, -(n as isize)),
+                0x00E0 => self.clear(self.planes),
+                0x00EE => self.pc = self.stack.pop().ok_or(Fault::StackUnderflow { pc })?,
+                0x00FB => self.scroll(4, 0),
+                0x00FC => self.scroll(-4, 0),
+                0x00FD => {
+                    self.pc = pc;
+                    return Err(Fault::Exited { pc });
+                }
+                0x00FE | 0x00FF => {
+                    self.hires = opcode == 0x00FF;
+                    self.clear(0xFF);
+                }
+                _ => return Err(invalid),
+            },
+            0x1000 => self.pc = nnn,
+            0x2000 => {
+                if self.stack.len() == STACK_SIZE {
+                    return Err(Fault::StackOverflow { pc });
+                }
+                self.stack.push(self.pc);
+                self.pc = nnn;
+            }
+            0x3000 => self.skip_if(self.v[x] == nn),
+            0x4000 => self.skip_if(self.v[x] != nn),
+            0x5000 => match n {
+                0x0 => self.skip_if(self.v[x] == self.v[y]),
+                0x2 => {
+                    for (offset, register) in register_range(x, y).into_iter().enumerate() {
+                        let address = self.memory_index(pc, self.i as usize + offset)?;
+                        self.memory[address] = self.v[register];
+                    }
+                }
+                0x3 => {
+                    for (offset, register) in register_range(x, y).into_iter().enumerate() {
+                        let address = self.memory_index(pc, self.i as usize + offset)?;
+                        self.v[register] = self.memory[address];
+                    }
+                }
+                _ => return Err(invalid),
+            },
+            0x6000 => self.v[x] = nn,
+            0x7000 => self.v[x] = self.v[x].wrapping_add(nn),
+            0x8000 => match n {
+                0x0 => self.v[x] = self.v[y],
+                0x1..=0x3 => {
+                    match n {
+                        0x1 => self.v[x] |= self.v[y],
+                        0x2 => self.v[x] &= self.v[y],
+                        _ => self.v[x] ^= self.v[y],
+                    }
+                    if self.quirks.logic_resets_vf {
+                        self.v[0xF] = 0;
+                    }
+                }
+                0x4 => {
+                    let (result, carry) = self.v[x].overflowing_add(self.v[y]);
+                    self.v[x] = result;
+                    self.v[0xF] = carry as u8;
+                }
+                0x5 => {
+                    let (result, borrow) = self.v[x].overflowing_sub(self.v[y]);
+                    self.v[x] = result;
+                    self.v[0xF] = !borrow as u8;
+                }
+                0x6 => {
+                    let value = self.shift_source(x, y);
+                    let flag = value & 1;
+                    self.v[x] = value >> 1;
+                    self.v[0xF] = flag;
+                }
+                0x7 => {
+                    let (result, borrow) = self.v[y].overflowing_sub(self.v[x]);
+                    self.v[x] = result;
+                    self.v[0xF] = !borrow as u8;
+                }
+                0xE => {
+                    let value = self.shift_source(x, y);
+                    let flag = value >> 7;
+                    self.v[x] = value << 1;
+                    self.v[0xF] = flag;
+                }
+                _ => return Err(invalid),
+            },
+            0x9000 if n == 0 => self.skip_if(self.v[x] != self.v[y]),
+            0xA000 => self.i = nnn,
+            0xB000 => {
+                let offset = if self.quirks.jump_uses_vx {
+                    self.v[x]
+                } else {
+                    self.v[0]
+                };
+                self.pc = self.wrap((nnn + offset as u16) as usize);
+            }
+            0xC000 => self.v[x] = self.random() & nn,
+            0xD000 => self.draw(pc, self.v[x], self.v[y], n)?,
+            0xE000 => match nn {
+                0x9E => self.skip_if(self.keys[(self.v[x] & 0xF) as usize]),
+                0xA1 => self.skip_if(!self.keys[(self.v[x] & 0xF) as usize]),
+                _ => return Err(invalid),
+            },
+            0xF000 => match nn {
+                0x00 if x == 0 => {
+                    let high = self.memory_index(pc, pc as usize + 2)?;
+                    let low = self.memory_index(pc, pc as usize + 3)?;
+                    self.i = u16::from_be_bytes([self.memory[high], self.memory[low]]);
+                    self.pc = self.wrap(pc as usize + 4);
+                }
+                0x01 => self.planes = x as u8 & 0x3,
+                0x02 if x == 0 => {
+                    for offset in 0..self.pattern.len() {
+                        let address = self.memory_index(pc, self.i as usize + offset)?;
+                        self.pattern[offset] = self.memory[address];
+                    }
+                }
+                0x07 => self.v[x] = self.delay_timer,
+                // like the COSMAC VIP, `wait` finishes when the key is released,
+                // running again until then
+                0x0A => match self.waiting_key {
+                    Some(key) if !self.keys[key as usize] => {
+                        self.v[x] = key;
+                        self.waiting_key = None;
+                    }
+                    Some(_) => self.pc = pc,
+                    None => {
+                        self.waiting_key = self
+                            .keys
+                            .iter()
+                            .position(|pressed| *pressed)
+                            .map(|key| key as u8);
+                        self.pc = pc;
+                    }
+                },
+                0x15 => self.delay_timer = self.v[x],
+                0x18 => self.sound_timer = self.v[x],
+                0x1E => self.i = self.i.wrapping_add(self.v[x] as u16),
+                0x29 => self.i = (FONT_START + (self.v[x] & 0xF) as usize * 5) as u16,
+                0x30 => self.i = (BIG_FONT_START + (self.v[x] & 0xF) as usize * 10) as u16,
+                0x3A => self.pitch = self.v[x],
+                0x33 => {
+                    let value = self.v[x];
+                    for (offset, digit) in [value / 100, value / 10 % 10, value % 10]
+                        .into_iter()
+                        .enumerate()
+                    {
+                        let address = self.memory_index(pc, self.i as usize + offset)?;
+                        self.memory[address] = digit;
+                    }
+                }
+                0x55 => {
+                    for register in 0..=x {
+                        let address = self.memory_index(pc, self.i as usize + register)?;
+                        self.memory[address] = self.v[register];
+                    }
+                    self.increment_i(x);
+                }
+                0x65 => {
+                    for register in 0..=x {
+                        let address = self.memory_index(pc, self.i as usize + register)?;
+                        self.v[register] = self.memory[address];
+                    }
+                    self.increment_i(x);
+                }
+                0x75 if x < self.flags.len() => self.flags[..=x].copy_from_slice(&self.v[..=x]),
+                0x85 if x < self.flags.len() => {
+                    let flags = self.flags;
+                    self.v[..=x].copy_from_slice(&flags[..=x]);
+                }
+                _ => return Err(invalid),
+            },
+            _ => return Err(invalid),
+        }
+
+        Ok(())
+    }
+
+    fn shift_source(&self, x: usize, y: usize) -> u8 {
+        if self.quirks.shift_uses_vy {
+            self.v[y]
+        } else {
+            self.v[x]
+        }
+    }
+
+    /// Moves I on after `save` or `load` of v0..=vx.
+    fn increment_i(&mut self, x: usize) {
+        let by = match self.quirks.memory_increment {
+            MemoryIncrement::None => return,
+            MemoryIncrement::ByX => x,
+            MemoryIncrement::ByXPlusOne => x + 1,
+        };
+        self.i = self.i.wrapping_add(by as u16);
+    }
+
+    /// Skips the next instruction, all 4 bytes of it for `mov i,long` as
+    /// on XO-CHIP.
+    fn skip_if(&mut self, condition: bool) {
+        if condition {
+            let size = if self.opcode() == 0xF000 { 4 } else { 2 };
+            self.pc = self.wrap(self.pc as usize + size);
+        }
+    }
+
+    /// Turns off every pixel on `planes`.
+    fn clear(&mut self, planes: u8) {
+        for pixel in self.display.iter_mut().flatten() {
+            *pixel &= !planes;
+        }
+        self.display_changed = true;
+    }
+
+    /// Moves the selected planes `dx` pixels right and `dy` down, filling
+    /// in blank.
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let (width, height) = (self.width() as isize, self.height() as isize);
+        let old = self.display;
+        self.clear(self.planes);
+        for y in 0..height {
+            for x in 0..width {
+                let (from_x, from_y) = (x - dx, y - dy);
+                if (0..width).contains(&from_x) && (0..height).contains(&from_y) {
+                    self.display[y as usize][x as usize] |=
+                        old[from_y as usize][from_x as usize] & self.planes;
+                }
+            }
+        }
+    }
+
+    /// Draws the `rows`-tall sprite at `i`, wrapping the starting position.
+    /// No rows draws a 16x16 sprite, two bytes a row. The rest clips at the
+    /// edges, or wraps with the quirk. VF is set when any pixel is turned off.
+    ///
+    /// With both planes selected the data for the second follows the first.
+    fn draw(&mut self, pc: u16, x: u8, y: u8, rows: u8) -> Result<(), Fault> {
+        let (width, height) = (self.width(), self.height());
+        let x = x as usize % width;
+        let y = y as usize % height;
+        self.v[0xF] = 0;
+
+        let (rows, columns) = match rows {
+            0 => (16, 16),
+            rows => (rows as usize, 8),
+        };
+        let bytes_per_row = columns / 8;
+        let wraps = self.quirks.draw_wraps;
+        let mut data = self.i as usize;
+        for plane in [1, 2] {
+            if self.planes & plane == 0 {
+                continue;
+            }
+
+            for row in 0..rows {
+                if y + row >= height && !wraps {
+                    break;
+                }
+
+                let mut bits = 0;
+                for byte in 0..bytes_per_row {
+                    let address = self.memory_index(pc, data + row * bytes_per_row + byte)?;
+                    bits = bits << 8 | self.memory[address] as u16;
+                }
+                for column in 0..columns {
+                    if x + column >= width && !wraps {
+                        break;
+                    }
+                    if bits & (1 << (columns - 1 - column)) == 0 {
+                        continue;
+                    }
+
+                    let pixel = &mut self.display[(y + row) % height][(x + column) % width];
+                    if *pixel & plane != 0 {
+                        self.v[0xF] = 1;
+                    }
+                    *pixel ^= plane;
+                }
+            }
+            data += rows * bytes_per_row;
+        }
+
+        self.display_changed = true;
+        self.drawn = true;
+        Ok(())
+    }
+}
+
+/// v`x` thru v`y`, backwards when `y` is lower, as `save vx-vy` stores them.
+fn register_range(x: usize, y: usize) -> Vec<usize> {
+    if x <= y {
+        (x..=y).collect()
+    } else {
+        (y..=x).rev().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machine(opcodes: &[u16]) -> Machine {
+        let rom: Vec<u8> = opcodes
+            .iter()
+            .flat_map(|opcode| opcode.to_be_bytes())
+            .collect();
+        Machine::new(&rom).unwrap()
+    }
+
+    fn run(machine: &mut Machine, steps: usize) {
+        for _ in 0..steps {
+            machine.step().unwrap();
+        }
+    }
+
+    fn lit(machine: &Machine) -> Vec<(usize, usize)> {
+        let mut pixels = vec![];
+        for (y, row) in machine.display.iter().enumerate() {
+            for (x, pixel) in row.iter().enumerate() {
+                if *pixel != 0 {
+                    pixels.push((x, y));
+                }
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn flag_overwrites_vf_result() {
+        // vf = 0xff + 2 carries, so vf ends up as the carry
+        let mut m = machine(&[0x6FFF, 0x6E02, 0x8FE4]);
+        run(&mut m, 3);
+        assert_eq!(m.v[0xF], 1);
+
+        let mut m = machine(&[0x6F01, 0x6E02, 0x8FE4]);
+        run(&mut m, 3);
+        assert_eq!(m.v[0xF], 0);
+
+        // 5 - 3 doesn't borrow
+        let mut m = machine(&[0x6F05, 0x6E03, 0x8FE5]);
+        run(&mut m, 3);
+        assert_eq!(m.v[0xF], 1);
+
+        let mut m = machine(&[0x6F03, 0x6E05, 0x8FE5]);
+        run(&mut m, 3);
+        assert_eq!(m.v[0xF], 0);
+
+        let mut m = machine(&[0x6F03, 0x6E05, 0x8FE7]);
+        run(&mut m, 3);
+        assert_eq!(m.v[0xF], 1);
+
+        let mut m = machine(&[0x6F05, 0x6E03, 0x8FE7]);
+        run(&mut m, 3);
+        assert_eq!(m.v[0xF], 0);
+    }
+
+    #[test]
+    fn flags_into_other_registers() {
+        let mut m = machine(&[0x60FF, 0x6102, 0x8014]);
+        run(&mut m, 3);
+        assert_eq!((m.v[0], m.v[0xF]), (0x01, 1));
+
+        let mut m = machine(&[0x6003, 0x6105, 0x8015]);
+        run(&mut m, 3);
+        assert_eq!((m.v[0], m.v[0xF]), (0xFE, 0));
+
+        let mut m = machine(&[0x6003, 0x6105, 0x8017]);
+        run(&mut m, 3);
+        assert_eq!((m.v[0], m.v[0xF]), (0x02, 1));
+    }
+
+    #[test]
+    fn bcd() {
+        let mut m = machine(&[0x60EA, 0xA300, 0xF033]);
+        run(&mut m, 3);
+        assert_eq!(m.memory[0x300..0x303], [2, 3, 4]);
+        assert_eq!(m.i, 0x300);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut m = machine(&[0x6011, 0x6122, 0x6233, 0xA300, 0xF255, 0x6000, 0xF165]);
+        run(&mut m, 5);
+        assert_eq!(m.memory[0x300..0x304], [0x11, 0x22, 0x33, 0]);
+
+        run(&mut m, 2);
+        assert_eq!(m.v[..3], [0x11, 0x22, 0x33]);
+        // modern leaves i alone
+        assert_eq!(m.i, 0x300);
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let mut m = machine(&[0x2200]);
+        run(&mut m, STACK_SIZE);
+        assert_eq!(m.stack.len(), STACK_SIZE);
+        assert_eq!(m.step(), Err(Fault::StackOverflow { pc: 0x200 }));
+    }
+
+    #[test]
+    fn stack_underflow() {
+        let mut m = machine(&[0x00EE]);
+        assert_eq!(m.step(), Err(Fault::StackUnderflow { pc: 0x200 }));
+    }
+
+    #[test]
+    fn call_and_return() {
+        let mut m = machine(&[0x2204, 0x0000, 0x00EE]);
+        run(&mut m, 1);
+        assert_eq!((m.pc, m.stack.as_slice()), (0x204, [0x202].as_slice()));
+        run(&mut m, 1);
+        assert_eq!(m.pc, 0x202);
+        assert!(m.stack.is_empty());
+    }
+
+    #[test]
+    fn draw_collision() {
+        // the font's 0 is 4 pixels wide
+        let mut m = machine(&[0xF029, 0xD015, 0xD015]);
+        run(&mut m, 2);
+        assert_eq!(m.v[0xF], 0);
+        assert_eq!(lit(&m).len(), 14);
+
+        run(&mut m, 1);
+        assert_eq!(m.v[0xF], 1);
+        assert!(lit(&m).is_empty());
+    }
+
+    #[test]
+    fn draw_clips() {
+        let mut m = machine(&[0x603E, 0x611E, 0xA300, 0xD012]);
+        m.memory[0x300] = 0xFF;
+        m.memory[0x301] = 0xFF;
+        m.memory[0x302] = 0xFF;
+        run(&mut m, 4);
+        assert_eq!(lit(&m), [(62, 30), (63, 30), (62, 31), (63, 31)]);
+        assert_eq!(m.v[0xF], 0);
+    }
+
+    #[test]
+    fn draw_wraps_start_position() {
+        // the starting position always wraps, only the rest clips
+        let mut m = machine(&[0x6042, 0x6121, 0xA300, 0xD011]);
+        m.memory[0x300] = 0x80;
+        run(&mut m, 4);
+        assert_eq!(lit(&m), [(2, 1)]);
+    }
+
+    #[test]
+    fn wait_for_release() {
+        let mut m = machine(&[0xF30A, 0x0000]);
+        run(&mut m, 2);
+        assert_eq!(m.pc, 0x200);
+
+        m.set_key(0x7, true);
+        run(&mut m, 2);
+        // still held
+        assert_eq!(m.pc, 0x200);
+        assert_eq!(m.v[3], 0);
+
+        m.set_key(0x7, false);
+        run(&mut m, 1);
+        assert_eq!(m.pc, 0x202);
+        assert_eq!(m.v[3], 0x7);
+    }
+
+    fn with_profile(opcodes: &[u16], profile: Profile) -> Machine {
+        machine(opcodes).with_quirks(profile.quirks())
+    }
+
+    #[test]
+    fn shift_source() {
+        let mut m = with_profile(&[0x6003, 0x6104, 0x8016], Profile::Modern);
+        run(&mut m, 3);
+        assert_eq!(m.v[0], 1);
+
+        let mut m = with_profile(&[0x6003, 0x6104, 0x8016], Profile::CosmacVip);
+        run(&mut m, 3);
+        assert_eq!(m.v[0], 2);
+
+        let mut m = with_profile(&[0x6003, 0x6184, 0x801E], Profile::CosmacVip);
+        run(&mut m, 3);
+        assert_eq!((m.v[0], m.v[0xF]), (0x08, 1));
+    }
+
+    #[test]
+    fn memory_increment() {
+        for (profile, i) in [
+            (Profile::Modern, 0x300),
+            (Profile::Schip11, 0x300),
+            (Profile::Chip48, 0x302),
+            (Profile::CosmacVip, 0x303),
+        ] {
+            let mut m = with_profile(&[0xA300, 0xF255], profile);
+            run(&mut m, 2);
+            assert_eq!(m.i, i, "save under {}", profile);
+
+            let mut m = with_profile(&[0xA300, 0xF265], profile);
+            run(&mut m, 2);
+            assert_eq!(m.i, i, "load under {}", profile);
+        }
+    }
+
+    #[test]
+    fn jump_register() {
+        let mut m = with_profile(&[0x6002, 0x6204, 0xB210], Profile::Modern);
+        run(&mut m, 3);
+        assert_eq!(m.pc, 0x212);
+
+        let mut m = with_profile(&[0x6002, 0x6204, 0xB210], Profile::Chip48);
+        run(&mut m, 3);
+        assert_eq!(m.pc, 0x214);
+    }
+
+    #[test]
+    fn logic_resets_vf() {
+        for opcode in [0x8011, 0x8012, 0x8013] {
+            let mut m = with_profile(&[0x6F05, opcode], Profile::Modern);
+            run(&mut m, 2);
+            assert_eq!(m.v[0xF], 5);
+
+            let mut m = with_profile(&[0x6F05, opcode], Profile::CosmacVip);
+            run(&mut m, 2);
+            assert_eq!(m.v[0xF], 0);
+        }
+    }
+
+    #[test]
+    fn draw_wraps() {
+        let opcodes = [0x603E, 0x611F, 0xA300, 0xD012];
+        let mut m = with_profile(&opcodes, Profile::XoChip);
+        m.memory[0x300] = 0xF0;
+        m.memory[0x301] = 0xF0;
+        run(&mut m, 4);
+        assert_eq!(
+            lit(&m),
+            [
+                (0, 0),
+                (1, 0),
+                (62, 0),
+                (63, 0),
+                (0, 31),
+                (1, 31),
+                (62, 31),
+                (63, 31)
+            ]
+        );
+
+        let mut m = with_profile(&opcodes, Profile::Modern);
+        m.memory[0x300] = 0xF0;
+        m.memory[0x301] = 0xF0;
+        run(&mut m, 4);
+        assert_eq!(lit(&m), [(62, 31), (63, 31)]);
+    }
+
+    #[test]
+    fn display_wait() {
+        // draw, then count up forever
+        let opcodes = [0xD011, 0x7101, 0x1202];
+        let mut m = with_profile(&opcodes, Profile::Modern);
+        m.frame().unwrap();
+        assert_eq!(m.v[1], 5);
+
+        let mut m = with_profile(&opcodes, Profile::CosmacVip);
+        m.frame().unwrap();
+        assert_eq!(m.v[1], 0);
+        // the next frame has all 11 instructions for the loop
+        m.frame().unwrap();
+        assert_eq!(m.v[1], 6);
+    }
+
+    #[test]
+    fn for_target() {
+        // `mov v0,1` then `jmp` to itself, wherever it was put
+        let mut m = Machine::for_target(&[0x60, 0x01, 0x16, 0x02], Target::Eti660).unwrap();
+        assert_eq!(m.pc, 0x600);
+        assert_eq!(m.memory[0x600..0x604], [0x60, 0x01, 0x16, 0x02]);
+        assert_eq!((m.width(), m.height()), (64, 48));
+        run(&mut m, 2);
+        assert_eq!((m.v[0], m.pc), (1, 0x602));
+
+        let mut rom = vec![0x12, 0x60];
+        rom.resize(0x44, 0);
+        rom.extend([0x60, 0x01, 0x12, 0x46]);
+        let mut m = Machine::for_target(&rom, Target::Hires).unwrap();
+        assert_eq!(m.pc, 0x244);
+        assert_eq!((m.width(), m.height()), (64, 64));
+        run(&mut m, 2);
+        assert_eq!((m.v[0], m.pc), (1, 0x246));
+
+        m.reset();
+        assert_eq!(m.pc, 0x244);
+    }
+}
@@ -1,86 +1,563 @@
 use crate::{
-    parser::{Program, Stmt, Stmt_::*},
-    Diagnostic,
+    diagnostic::{codes, has_errors, Diagnostic},
+    lexer::Span,
+    parser::{Expr, Op, Program, Stmt, Stmt_::*},
+    target::Target,
+    validate::{self, Width},
+    Options,
 };
 use std::collections::HashMap;
 
-struct Props<'a> {
-    pub pc: u16,
+struct Props {
+    /// Where the code starts, the target's entry point.
+    pub start: usize,
+    /// Offset of the statement being looked at from the start of the code.
+    pub pc: usize,
     pub ins: Vec<u8>,
-    pub labels: HashMap<&'a str, u16>,
-    pub sprites: HashMap<&'a str, u16>,
+    pub labels: HashMap<String, u16>,
+    pub sprites: HashMap<String, u16>,
+    pub constants: HashMap<String, u16>,
+    pub label_spans: HashMap<String, Span>,
+    pub sprite_spans: HashMap<String, Span>,
+    pub constant_spans: HashMap<String, Span>,
+    /// Each constant's value as written, and the span of it.
+    pub definitions: HashMap<String, (Expr, Span)>,
     pub sprite_data: Vec<u8>,
     pub line: usize,
-    pub skips: usize,
     pub code_size: usize,
+    pub addresses: Vec<u16>,
+    pub diagnostics: Vec<Diagnostic>,
 }
 
-const PROGRAM_START: usize = 0x200;
+/// Output of a successful `assemble`.
+pub struct Assembly {
+    /// The target's prefix, then code followed by sprite data, ready to be
+    /// loaded at the target's load address.
+    pub rom: Vec<u8>,
+    pub labels: HashMap<String, u16>,
+    pub sprites: HashMap<String, u16>,
+    pub constants: HashMap<String, u16>,
+    /// Address of every statement in `program.statements`, in the same order.
+    /// Labels map to the address they name, sprites to their data.
+    pub addresses: Vec<u16>,
+    pub program: Program,
+    pub target: Target,
+    /// Warnings reported while assembling.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Assembly {
+    /// Statements that emit code, with their addresses.
+    pub fn instructions(&self) -> impl Iterator<Item = (u16, &Stmt)> {
+        self.addresses
+            .iter()
+            .copied()
+            .zip(&self.program.statements)
+            .filter(|(_, stmt)| {
+                !matches!(
+                    stmt.node,
+                    DeclareLabel(_) | DeclareSprite(..) | DeclareConstant(..)
+                )
+            })
+    }
+}
 
-pub fn interp<'a>(program: &'a Program, diagnostic: &Diagnostic) -> Vec<u8> {
+/// Where classic CHIP-8 loads programs; see `Target` for the others.
+pub const PROGRAM_START: usize = 0x200;
+/// Classic CHIP-8's memory, and the most a 12-bit address reaches.
+pub const MEMORY_SIZE: usize = 0x1000;
+
+pub fn interp(mut program: Program, options: &Options) -> Result<Assembly, Vec<Diagnostic>> {
+    let target = options.target;
     let mut props = Props {
+        start: target.entry(),
         pc: 0,
         ins: vec![],
         labels: HashMap::new(),
         sprites: HashMap::new(),
+        constants: HashMap::new(),
+        label_spans: HashMap::new(),
+        sprite_spans: HashMap::new(),
+        constant_spans: HashMap::new(),
+        definitions: HashMap::new(),
         sprite_data: vec![],
         line: 0,
-        skips: 0,
         code_size: 0,
+        addresses: vec![],
+        diagnostics: vec![],
     };
 
-    // first phase: labels and code size
+    // first phase: labels and code size. sprites go after the code, so
+    // they take up no room here
     for expr in &program.statements {
-        props.pc += 2;
         props.line += 1;
-        interp_label(&mut props, diagnostic, expr);
+        interp_label(&mut props, expr);
+        if !matches!(expr.node, DeclareSprite(..)) {
+            props.pc += expr.node.size();
+        }
     }
 
-    props.code_size = (program.statements.len() - props.skips) * 2;
+    props.code_size = props.pc;
     props.pc = 0;
     props.line = 0;
 
+    // sprites go after the code, so they have their addresses now too
+    let mut sprite_address = props.start + props.code_size;
+    for stmt in &program.statements {
+        if let DeclareSprite(_, ref data) = stmt.node {
+            interp_sprite(&mut props, stmt, sprite_address);
+            sprite_address += data.len();
+        }
+    }
+
+    // every name has its value now, so expressions can be worked out
+    interp_constants(&mut props, &program);
+    for stmt in &mut program.statements {
+        interp_value(&mut props, stmt, options);
+    }
+
     for expr in &program.statements {
-        props.pc += 2;
         props.line += 1;
-        interp_stmt(&mut props, diagnostic, expr);
+
+        let offset = match expr.node {
+            DeclareSprite(_, _) => props.code_size + props.sprite_data.len(),
+            _ => props.ins.len(),
+        };
+        props.addresses.push((props.start + offset) as u16);
+
+        interp_stmt(&mut props, expr);
     }
 
-    props.ins.extend(props.sprite_data);
-    props.ins
+    check_budget(&mut props, &program, target);
+
+    if has_errors(&props.diagnostics) {
+        return Err(props.diagnostics);
+    }
+
+    let mut rom = target.prefix().to_vec();
+    rom.resize(props.start - target.load_address(), 0);
+    rom.extend(props.ins);
+    rom.extend(props.sprite_data);
+
+    Ok(Assembly {
+        rom,
+        labels: props.labels,
+        sprites: props.sprites,
+        constants: props.constants,
+        addresses: props.addresses,
+        diagnostics: props.diagnostics,
+        program,
+        target,
+    })
 }
 
-fn interp_label<'a>(props: &mut Props<'a>, diagnostic: &Diagnostic, stmt: &'a Stmt) {
-    match stmt.node {
-        DeclareLabel(ref id) => {
-            if props.labels.contains_key(id.as_str()) {
-                diagnostic.warn(stmt.span, format!("label {:?} is already declared", id));
+/// Reports the first statement that doesn't fit in `target`'s memory, and
+/// labels that `jmp` and `call` can't reach with their 12-bit address.
+fn check_budget(props: &mut Props, program: &Program, target: Target) {
+    let memory_size = target.memory_size();
+    let available = memory_size - props.start;
+    let total = props.ins.len() + props.sprite_data.len();
+
+    let mut overflow = None;
+    for (address, stmt) in props.addresses.iter().zip(&program.statements) {
+        let size = stmt.node.size();
+        let end = *address as usize + size;
+        if size > 0 && end > memory_size {
+            let first = overflow.get_or_insert((*address as usize, stmt.span));
+            if (*address as usize) < first.0 {
+                *first = (*address as usize, stmt.span);
             }
+        }
+
+        if let DeclareLabel(ref id) = stmt.node {
+            if *address as usize >= MEMORY_SIZE {
+                props.diagnostics.push(
+                    Diagnostic::error(
+                        stmt.span,
+                        format!(
+                            "label {:?} is at {:#x}, past the 12-bit address space",
+                            id, address
+                        ),
+                    )
+                    .with_code(codes::ADDRESS_OUT_OF_RANGE),
+                );
+            }
+        }
+    }
+
+    // point at the statement that crosses the end, not whatever is last.
+    // addresses wrap past 64 KiB, so fall back to the last statement
+    if overflow.is_none() && total > available {
+        overflow = program.statements.last().map(|stmt| (0, stmt.span));
+    }
+    if let Some((_, span)) = overflow {
+        props.diagnostics.push(
+            Diagnostic::error(
+                span,
+                format!(
+                    "program is {} bytes, {} more than the {} that fit from {:#05x} to {:#05x}",
+                    total,
+                    total - available,
+                    available,
+                    props.start,
+                    memory_size - 1
+                ),
+            )
+            .with_code(codes::ROM_TOO_LARGE),
+        );
+    }
+}
 
-            props.labels.insert(id, PROGRAM_START as u16 + props.pc - 2);
-            props.pc -= 2;
-            props.skips += 1;
+fn interp_label(props: &mut Props, stmt: &Stmt) {
+    if let DeclareLabel(ref id) = stmt.node {
+        if props.labels.contains_key(id.as_str()) {
+            props.diagnostics.push(
+                Diagnostic::warning(stmt.span, format!("label {:?} is already declared", id))
+                    .with_code(codes::DUPLICATE_LABEL)
+                    .with_label(props.label_spans[id.as_str()], "first declared here".into()),
+            );
         }
-        DeclareSprite(_, _) => {
-            props.pc -= 2;
-            props.skips += 1;
+
+        props.label_spans.insert(id.clone(), stmt.span);
+
+        props
+            .labels
+            .insert(id.clone(), (props.start + props.pc) as u16);
+    }
+}
+
+fn interp_sprite(props: &mut Props, stmt: &Stmt, address: usize) {
+    if let DeclareSprite(ref id, _) = stmt.node {
+        if props.sprites.contains_key(id.as_str()) {
+            props.diagnostics.push(
+                Diagnostic::warning(stmt.span, format!("sprite {:?} is already declared", id))
+                    .with_code(codes::DUPLICATE_SPRITE)
+                    .with_label(
+                        props.sprite_spans[id.as_str()],
+                        "first declared here".into(),
+                    ),
+            );
         }
-        _ => {}
+
+        props.sprites.insert(id.clone(), address as u16);
+        props.sprite_spans.insert(id.clone(), stmt.span);
     }
 }
 
-fn interp_stmt<'a>(props: &mut Props<'a>, diagnostic: &Diagnostic, stmt: &'a Stmt) {
-    match stmt.node {
-        DeclareLabel(_) => {}
-        DeclareSprite(ref id, ref data) => {
-            if props.sprites.contains_key(id.as_str()) {
-                diagnostic.warn(stmt.span, format!("sprite {:?} is already declared", id));
+/// Works out the value of every constant, and reports names declared both
+/// as a constant and as a label or sprite.
+fn interp_constants(props: &mut Props, program: &Program) {
+    for stmt in &program.statements {
+        if let DeclareConstant(ref id, ref value) = stmt.node {
+            if let Some(first) = props.constant_spans.get(id) {
+                props.diagnostics.push(
+                    Diagnostic::warning(
+                        stmt.span,
+                        format!("constant {:?} is already declared", id),
+                    )
+                    .with_code(codes::DUPLICATE_CONSTANT)
+                    .with_label(*first, "first declared here".into()),
+                );
             }
+            let span = stmt.operands.last().copied().unwrap_or(stmt.span);
+            props.constant_spans.insert(id.clone(), stmt.span);
+            props.definitions.insert(id.clone(), (value.clone(), span));
+        }
+    }
 
-            let sprite_location = PROGRAM_START + props.code_size + props.sprite_data.len();
-            props.sprites.insert(id, sprite_location as u16);
-            props.sprite_data.extend(data);
-            props.pc -= 2;
+    for stmt in &program.statements {
+        if let DeclareConstant(ref id, _) = stmt.node {
+            let other = match props.label_spans.get(id) {
+                Some(span) => Some(("label", *span)),
+                None => props.sprite_spans.get(id).map(|span| ("sprite", *span)),
+            };
+            if let Some((kind, span)) = other {
+                props.diagnostics.push(
+                    Diagnostic::error(
+                        stmt.span,
+                        format!("{:?} is declared as both a constant and a {}", id, kind),
+                    )
+                    .with_code(codes::NAME_CLASH)
+                    .with_label(span, format!("declared as a {} here", kind)),
+                );
+            }
+            symbol(props, id, stmt.span, &mut vec![]);
+        }
+    }
+}
+
+/// Value of the label, sprite or constant `id`, reporting it against
+/// `span` if it isn't declared. A constant is worked out from its
+/// definition the first time it is asked for; `visiting` holds the ones
+/// being worked out, to catch constants defined in terms of themselves.
+fn symbol(props: &mut Props, id: &str, span: Span, visiting: &mut Vec<String>) -> Option<u16> {
+    if let Some(value) = props.constants.get(id) {
+        return Some(*value);
+    }
+
+    if let Some((expr, value_span)) = props.definitions.get(id).cloned() {
+        if let Some(start) = visiting.iter().position(|constant| constant == id) {
+            props.diagnostics.push(
+                Diagnostic::error(
+                    span,
+                    format!(
+                        "constant {:?} is defined in terms of itself: {} = {}",
+                        id,
+                        visiting[start..].join(" = "),
+                        id
+                    ),
+                )
+                .with_code(codes::CIRCULAR_CONSTANT),
+            );
+            return None;
+        }
+
+        visiting.push(id.to_string());
+        let value = evaluate(props, &expr, value_span, visiting);
+        visiting.pop();
+        // one that can't be worked out has been reported already, so uses
+        // of it shouldn't be
+        props.constants.insert(id.to_string(), value.unwrap_or(0));
+        return value;
+    }
+
+    if let Some(address) = props.labels.get(id).or_else(|| props.sprites.get(id)) {
+        return Some(*address);
+    }
+
+    let labels = props.labels.keys().map(|name| (name.as_str(), "label"));
+    let sprites = props.sprites.keys().map(|name| (name.as_str(), "sprite"));
+    let constants = props
+        .definitions
+        .keys()
+        .map(|name| (name.as_str(), "constant"));
+    let candidates = labels.chain(sprites).chain(constants);
+    props.diagnostics.push(undeclared(
+        id,
+        span,
+        "label, sprite or constant",
+        codes::UNDECLARED_NAME,
+        candidates,
+    ));
+    None
+}
+
+/// Value of `expr`, or `None` when it can't be worked out, which has been
+/// reported. Arithmetic is on 16 bits and going past either end of them is
+/// an error, reported against `span`, the operand the expression is.
+fn evaluate(props: &mut Props, expr: &Expr, span: Span, visiting: &mut Vec<String>) -> Option<u16> {
+    let (op, a, b) = match *expr {
+        Expr::Int(int) => return Some(int),
+        Expr::Name(ref id, name_span) => return symbol(props, id, name_span, visiting),
+        Expr::Not(ref a) => return evaluate(props, a, span, visiting).map(|a| !a),
+        Expr::Binary(op, ref a, ref b) => (
+            op,
+            evaluate(props, a, span, visiting)?,
+            evaluate(props, b, span, visiting)?,
+        ),
+    };
+
+    let value = match op {
+        Op::Add => a.checked_add(b),
+        Op::Sub => a.checked_sub(b),
+        Op::Mul => a.checked_mul(b),
+        Op::Div | Op::Rem if b == 0 => {
+            props.diagnostics.push(
+                Diagnostic::error(span, format!("`{} {} {}` divides by zero", a, op, b))
+                    .with_code(codes::DIVIDE_BY_ZERO),
+            );
+            return None;
+        }
+        Op::Div => Some(a / b),
+        Op::Rem => Some(a % b),
+        Op::And => Some(a & b),
+        Op::Or => Some(a | b),
+        Op::Xor => Some(a ^ b),
+        Op::Shl => u16::try_from((a as u64) << b.min(16)).ok(),
+        Op::Shr => Some(a.checked_shr(b as u32).unwrap_or(0)),
+    };
+
+    if value.is_none() {
+        let message = match op {
+            Op::Sub => format!("`{} - {}` is below 0", a, b),
+            _ => format!("`{} {} {}` is past 16 bits", a, op, b),
+        };
+        props
+            .diagnostics
+            .push(Diagnostic::error(span, message).with_code(codes::OVERFLOW));
+    }
+    value
+}
+
+/// Works out the operands left in `stmt.values` and puts them into
+/// `stmt.node`, checking they fit there, the target has the instruction
+/// they make and how it behaves under the quirks profile.
+fn interp_value(props: &mut Props, stmt: &mut Stmt, options: &Options) {
+    // `validate` left these for here, as a `drw`'s row count decides which
+    // instruction it is and a `jmpr`'s address which register it adds
+    let deferred = !stmt.values.is_empty();
+
+    // `jmp`, `call` and `mov i` parse a bare name as a label or sprite, and
+    // one of another kind is worked out like any other name
+    let named = match stmt.node {
+        JumpLabel(ref id) | CallLabel(ref id) if props.labels.contains_key(id) => None,
+        MoveIRegisterSprite(ref id) | MoveIRegisterLongSprite(ref id)
+            if props.sprites.contains_key(id) =>
+        {
+            None
+        }
+        JumpLabel(ref id) => Some((JumpInteger(0), id)),
+        CallLabel(ref id) => Some((CallInteger(0), id)),
+        MoveIRegisterSprite(ref id) => Some((MoveIRegisterInteger(0), id)),
+        MoveIRegisterLongSprite(ref id) => Some((MoveIRegisterLong(0), id)),
+        _ => None,
+    };
+    if let Some((node, id)) = named {
+        if props.definitions.contains_key(id)
+            || props.labels.contains_key(id)
+            || props.sprites.contains_key(id)
+        {
+            let span = stmt.operands.last().copied().unwrap_or(stmt.span);
+            stmt.values = vec![(0, Expr::Name(id.clone(), span))];
+            stmt.node = node;
+        }
+    }
+
+    for (index, expr) in stmt.values.clone() {
+        // a sprite's bytes follow its name, and an instruction's integer
+        // operand is its last
+        let span = match stmt.node {
+            DeclareSprite(..) => stmt.operands.get(index + 1),
+            _ => stmt.operands.last(),
+        }
+        .copied()
+        .unwrap_or(stmt.span);
+        let Some(value) = evaluate(props, &expr, span, &mut vec![]) else {
+            continue;
+        };
+        stmt.node.set_immediate(index, value);
+
+        let diagnostics = match stmt.node {
+            // the bytes written as numbers were checked by `validate`
+            DeclareSprite(..) => validate::check(value, Width::Byte, span)
+                .into_iter()
+                .collect(),
+            _ => validate::operands(stmt),
+        };
+        // a value that doesn't fit is easier to make sense of next to where
+        // the constant it came from is declared
+        let declared = match expr {
+            Expr::Name(ref id, _) => props
+                .constant_spans
+                .get(id)
+                .map(|span| (*span, format!("{} is {} here", id, value))),
+            _ => None,
+        };
+        for mut diagnostic in diagnostics {
+            if let Some((span, ref message)) = declared {
+                diagnostic = diagnostic.with_label(span, message.clone());
+            }
+            props.diagnostics.push(diagnostic);
+        }
+    }
+    if deferred {
+        props
+            .diagnostics
+            .extend(validate::unsupported(stmt, options.target));
+        props
+            .diagnostics
+            .extend(validate::jump_register(stmt, options.quirks));
+    }
+}
+
+/// Looks up `id` in `table`, reporting it and falling back to address 0 so
+/// the rest of the program still gets checked.
+fn lookup(
+    table: &HashMap<String, u16>,
+    diagnostics: &mut Vec<Diagnostic>,
+    id: &str,
+    stmt: &Stmt,
+    kind: &str,
+    code: &'static str,
+) -> u16 {
+    if let Some(address) = table.get(id) {
+        return *address;
+    }
+
+    let span = stmt.operands.last().copied().unwrap_or(stmt.span);
+    let candidates = table.keys().map(|name| (name.as_str(), kind));
+    diagnostics.push(undeclared(id, span, kind, code, candidates));
+    0
+}
+
+/// Reports `id` as not declared, suggesting the closest of `candidates`,
+/// which are names with their kind.
+fn undeclared<'a>(
+    id: &str,
+    span: Span,
+    kind: &str,
+    code: &'static str,
+    candidates: impl Iterator<Item = (&'a str, &'a str)>,
+) -> Diagnostic {
+    let mut diagnostic =
+        Diagnostic::error(span, format!("{} {:?} is not declared", kind, id)).with_code(code);
+
+    let candidates: HashMap<&str, &str> = candidates.collect();
+    if let Some(candidate) = closest(id, candidates.keys().copied()) {
+        diagnostic = diagnostic.with_suggestion(
+            span,
+            candidate.to_string(),
+            format!(
+                "a {} with a similar name exists: `{}`",
+                candidates[candidate], candidate
+            ),
+        );
+    }
+
+    diagnostic
+}
+
+/// Most similar name to `name` among `candidates`, if any is close enough to
+/// plausibly be a typo.
+fn closest<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let limit = name.len().div_ceil(3);
+
+    candidates
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= limit)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                diagonal
+            } else {
+                1 + diagonal.min(above).min(row[j])
+            };
+            diagonal = above;
+        }
+    }
+
+    row[b.len()]
+}
+
+fn interp_stmt(props: &mut Props, stmt: &Stmt) {
+    match stmt.node {
+        DeclareLabel(_) | DeclareConstant(..) => {}
+        DeclareSprite(_, ref data) => {
+            props
+                .sprite_data
+                .extend(data.iter().map(|byte| *byte as u8));
         }
         Nop => {
             props.ins.extend(vec![0x00, 0x00]);
@@ -97,11 +574,14 @@ fn interp_stmt<'a>(props: &mut Props<'a>, diagnostic: &Diagnostic, stmt: &'a Stm
             props.ins.extend(vec![high_byte as u8, low_byte as u8]);
         }
         JumpLabel(ref id) => {
-            if !props.labels.contains_key(id.as_str()) {
-                diagnostic.error(stmt.span, format!("label {:?} is not declared", id));
-            }
-
-            let pc = props.labels.get(id.as_str()).unwrap();
+            let pc = lookup(
+                &props.labels,
+                &mut props.diagnostics,
+                id,
+                stmt,
+                "label",
+                codes::UNDECLARED_LABEL,
+            );
             let high_byte = 0x10 + ((pc & 0xF00) >> 8);
             let low_byte = pc & 0x0FF;
             props.ins.extend(vec![high_byte as u8, low_byte as u8]);
@@ -112,89 +592,92 @@ fn interp_stmt<'a>(props: &mut Props<'a>, diagnostic: &Diagnostic, stmt: &'a Stm
             props.ins.extend(vec![high_byte as u8, low_byte as u8]);
         }
         CallLabel(ref id) => {
-            if !props.labels.contains_key(id.as_str()) {
-                diagnostic.error(stmt.span, format!("label {:?} is not declared", id));
-            }
-
-            let pc = props.labels.get(id.as_str()).unwrap();
+            let pc = lookup(
+                &props.labels,
+                &mut props.diagnostics,
+                id,
+                stmt,
+                "label",
+                codes::UNDECLARED_LABEL,
+            );
             let high_byte = 0x20 + ((pc & 0xF00) >> 8);
             let low_byte = pc & 0x0FF;
             props.ins.extend(vec![high_byte as u8, low_byte as u8]);
         }
         SkipEqualsInteger(ref x, ref nn) => {
             let high_byte = 0x30 + x;
-            let low_byte = *nn;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            let low_byte = *nn as u8;
+            props.ins.extend(vec![high_byte, low_byte]);
         }
         SkipNotEqualsInteger(ref x, ref nn) => {
             let high_byte = 0x40 + x;
-            let low_byte = *nn;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            let low_byte = *nn as u8;
+            props.ins.extend(vec![high_byte, low_byte]);
         }
         SkipEqualsRegister(ref x, ref y) => {
             let high_byte = 0x50 + x;
             let low_byte = y << 4;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            props.ins.extend(vec![high_byte, low_byte]);
         }
         MoveRegisterInteger(ref x, ref nn) => {
             let high_byte = 0x60 + x;
-            let low_byte = *nn;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            let low_byte = *nn as u8;
+            props.ins.extend(vec![high_byte, low_byte]);
         }
         AddRegisterInteger(ref x, ref nn) => {
             let high_byte = 0x70 + x;
-            let low_byte = *nn;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            let low_byte = *nn as u8;
+            props.ins.extend(vec![high_byte, low_byte]);
         }
         MoveRegisterRegister(ref x, ref y) => {
             let high_byte = 0x80 + x;
             let low_byte = y << 4;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            props.ins.extend(vec![high_byte, low_byte]);
         }
         Or(ref x, ref y) => {
             let high_byte = 0x80 + x;
             let low_byte = (y << 4) + 1;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            props.ins.extend(vec![high_byte, low_byte]);
         }
         And(ref x, ref y) => {
             let high_byte = 0x80 + x;
             let low_byte = (y << 4) + 2;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            props.ins.extend(vec![high_byte, low_byte]);
         }
         Xor(ref x, ref y) => {
             let high_byte = 0x80 + x;
             let low_byte = (y << 4) + 3;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            props.ins.extend(vec![high_byte, low_byte]);
         }
         AddRegisterRegister(ref x, ref y) => {
             let high_byte = 0x80 + x;
             let low_byte = (y << 4) + 4;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            props.ins.extend(vec![high_byte, low_byte]);
         }
         Subtract(ref x, ref y) => {
             let high_byte = 0x80 + x;
             let low_byte = (y << 4) + 5;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            props.ins.extend(vec![high_byte, low_byte]);
         }
         ShiftRight(ref x) => {
             let high_byte = 0x80 + x;
             let low_byte = 0x06;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            props.ins.extend(vec![high_byte, low_byte as u8]);
         }
         SubtractReverse(ref x, ref y) => {
             let high_byte = 0x80 + x;
             let low_byte = (y << 4) + 7;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            props.ins.extend(vec![high_byte, low_byte]);
         }
         ShiftLeft(ref x) => {
             let high_byte = 0x80 + x;
             let low_byte = 0x0E;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            props.ins.extend(vec![high_byte, low_byte as u8]);
         }
         SkipNotEqualsRegister(ref x, ref y) => {
             let high_byte = 0x90 + x;
             let low_byte = y << 4;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            props.ins.extend(vec![high_byte, low_byte]);
         }
         MoveIRegisterInteger(ref nnn) => {
             let high_byte = 0xA0 + ((nnn & 0xF00) >> 8);
@@ -202,11 +685,32 @@ fn interp_stmt<'a>(props: &mut Props<'a>, diagnostic: &Diagnostic, stmt: &'a Stm
             props.ins.extend(vec![high_byte as u8, low_byte as u8]);
         }
         MoveIRegisterSprite(ref id) => {
-            if !props.sprites.contains_key(id.as_str()) {
-                diagnostic.error(stmt.span, format!("sprite {:?} is not declared", id));
+            let pc = lookup(
+                &props.sprites,
+                &mut props.diagnostics,
+                id,
+                stmt,
+                "sprite",
+                codes::UNDECLARED_SPRITE,
+            );
+            if pc as usize >= MEMORY_SIZE {
+                let span = stmt.operands.last().copied().unwrap_or(stmt.span);
+                props.diagnostics.push(
+                    Diagnostic::error(
+                        span,
+                        format!(
+                            "sprite {:?} is at {:#x}, past the 12 bits `mov i` can address",
+                            id, pc
+                        ),
+                    )
+                    .with_code(codes::ADDRESS_OUT_OF_RANGE)
+                    .with_suggestion(
+                        span,
+                        format!("long {}", id),
+                        "`mov i,long` reaches all of memory on xochip".to_string(),
+                    ),
+                );
             }
-
-            let pc = props.sprites.get(id.as_str()).unwrap();
             let high_byte = 0xA0 + ((pc & 0xF00) >> 8);
             let low_byte = pc & 0x0FF;
             props.ins.extend(vec![high_byte as u8, low_byte as u8]);
@@ -218,68 +722,345 @@ fn interp_stmt<'a>(props: &mut Props<'a>, diagnostic: &Diagnostic, stmt: &'a Stm
         }
         Random(ref x, ref nn) => {
             let high_byte = 0xC0 + x;
-            let low_byte = *nn;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            let low_byte = *nn as u8;
+            props.ins.extend(vec![high_byte, low_byte]);
         }
         Draw(ref x, ref y, ref n) => {
             let high_byte = 0xD0 + x;
-            let low_byte = (y << 4) + n;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            let low_byte = (y << 4) + *n as u8;
+            props.ins.extend(vec![high_byte, low_byte]);
         }
         SkipKeyPressed(ref x) => {
             let high_byte = 0xE0 + x;
             let low_byte = 0x9E;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            props.ins.extend(vec![high_byte, low_byte as u8]);
         }
         SkipKeyNotPressed(ref x) => {
             let high_byte = 0xE0 + x;
             let low_byte = 0xA1;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            props.ins.extend(vec![high_byte, low_byte as u8]);
         }
         MoveRegisterDelay(ref x) => {
             let high_byte = 0xF0 + x;
             let low_byte = 0x07;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            props.ins.extend(vec![high_byte, low_byte as u8]);
         }
         WaitKeyPress(ref x) => {
             let high_byte = 0xF0 + x;
             let low_byte = 0x0A;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            props.ins.extend(vec![high_byte, low_byte as u8]);
         }
         MoveDelayRegister(ref x) => {
             let high_byte = 0xF0 + x;
             let low_byte = 0x15;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            props.ins.extend(vec![high_byte, low_byte as u8]);
         }
         MoveSoundRegister(ref x) => {
             let high_byte = 0xF0 + x;
             let low_byte = 0x18;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            props.ins.extend(vec![high_byte, low_byte as u8]);
         }
         AddIRegisterRegister(ref x) => {
             let high_byte = 0xF0 + x;
             let low_byte = 0x1E;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            props.ins.extend(vec![high_byte, low_byte as u8]);
         }
         Sprite(ref x) => {
             let high_byte = 0xF0 + x;
             let low_byte = 0x29;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            props.ins.extend(vec![high_byte, low_byte as u8]);
         }
         Bcd(ref x) => {
             let high_byte = 0xF0 + x;
             let low_byte = 0x33;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            props.ins.extend(vec![high_byte, low_byte as u8]);
         }
         Save(ref x) => {
             let high_byte = 0xF0 + x;
             let low_byte = 0x55;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            props.ins.extend(vec![high_byte, low_byte as u8]);
         }
         Load(ref x) => {
             let high_byte = 0xF0 + x;
             let low_byte = 0x65;
-            props.ins.extend(vec![high_byte as u8, low_byte as u8]);
+            props.ins.extend(vec![high_byte, low_byte as u8]);
+        }
+        Word(ref nnnn) => {
+            props.ins.extend(nnnn.to_be_bytes());
+        }
+        ScrollDown(ref n) => {
+            props.ins.extend(vec![0x00, 0xC0 + *n as u8]);
+        }
+        ScrollRight => {
+            props.ins.extend(vec![0x00, 0xFB]);
+        }
+        ScrollLeft => {
+            props.ins.extend(vec![0x00, 0xFC]);
+        }
+        Exit => {
+            props.ins.extend(vec![0x00, 0xFD]);
+        }
+        LowRes => {
+            props.ins.extend(vec![0x00, 0xFE]);
+        }
+        HighRes => {
+            props.ins.extend(vec![0x00, 0xFF]);
+        }
+        BigSprite(ref x) => {
+            let high_byte = 0xF0 + x;
+            let low_byte = 0x30;
+            props.ins.extend(vec![high_byte, low_byte as u8]);
+        }
+        SaveFlags(ref x) => {
+            let high_byte = 0xF0 + x;
+            let low_byte = 0x75;
+            props.ins.extend(vec![high_byte, low_byte as u8]);
+        }
+        LoadFlags(ref x) => {
+            let high_byte = 0xF0 + x;
+            let low_byte = 0x85;
+            props.ins.extend(vec![high_byte, low_byte as u8]);
+        }
+        SaveRange(ref x, ref y) => {
+            let high_byte = 0x50 + x;
+            let low_byte = (y << 4) + 2;
+            props.ins.extend(vec![high_byte, low_byte]);
+        }
+        LoadRange(ref x, ref y) => {
+            let high_byte = 0x50 + x;
+            let low_byte = (y << 4) + 3;
+            props.ins.extend(vec![high_byte, low_byte]);
+        }
+        MoveIRegisterLong(ref nnnn) => {
+            let [high_byte, low_byte] = nnnn.to_be_bytes();
+            props.ins.extend(vec![0xF0, 0x00, high_byte, low_byte]);
+        }
+        MoveIRegisterLongSprite(ref id) => {
+            let pc = lookup(
+                &props.sprites,
+                &mut props.diagnostics,
+                id,
+                stmt,
+                "sprite",
+                codes::UNDECLARED_SPRITE,
+            );
+            let [high_byte, low_byte] = pc.to_be_bytes();
+            props.ins.extend(vec![0xF0, 0x00, high_byte, low_byte]);
         }
+        Plane(ref n) => {
+            let high_byte = 0xF0 + *n as u8;
+            let low_byte = 0x01;
+            props.ins.extend(vec![high_byte, low_byte]);
+        }
+        Audio => {
+            props.ins.extend(vec![0xF0, 0x02]);
+        }
+        Pitch(ref x) => {
+            let high_byte = 0xF0 + x;
+            let low_byte = 0x3A;
+            props.ins.extend(vec![high_byte, low_byte]);
+        }
+        ScrollUp(ref n) => {
+            props.ins.extend(vec![0x00, 0xD0 + *n as u8]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assemble, assemble_with, diagnostic::codes, quirks::Profile, Options};
+
+    use super::*;
+
+    fn rom(source: &str) -> Vec<u8> {
+        match assemble(source) {
+            Ok(assembly) => assembly.rom,
+            Err(diagnostics) => panic!("{:?}", diagnostics),
+        }
+    }
+
+    /// Every diagnostic for `source`, which must fail.
+    fn diagnostics(source: &str) -> Vec<Diagnostic> {
+        match assemble(source) {
+            Ok(_) => panic!("{:?} assembled", source),
+            Err(diagnostics) => diagnostics,
+        }
+    }
+
+    fn errors(source: &str) -> Vec<&'static str> {
+        diagnostics(source).iter().filter_map(|d| d.code).collect()
+    }
+
+    #[test]
+    fn budget() {
+        // 0x200 to 0xfff is 3584 bytes
+        let full = "cls\n".repeat(1792);
+        assert_eq!(rom(&full).len(), 3584);
+
+        let over = format!("{}ret\n", full);
+        let diagnostics = diagnostics(&over);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, Some(codes::ROM_TOO_LARGE));
+        assert_eq!(&over[diagnostics[0].span.lo..diagnostics[0].span.hi], "ret");
+
+        assert_eq!(
+            errors(&format!("{}end:\nret\n", full)),
+            [codes::ADDRESS_OUT_OF_RANGE, codes::ROM_TOO_LARGE]
+        );
+    }
+
+    #[test]
+    fn operators() {
+        assert_eq!(rom("mov v0,2 + 3 * 4"), [0x60, 14]);
+        assert_eq!(rom("mov v0,(2 + 3) * 4"), [0x60, 20]);
+        assert_eq!(rom("mov v0,17 / 5"), [0x60, 3]);
+        assert_eq!(rom("mov v0,17 % 5"), [0x60, 2]);
+        assert_eq!(rom("mov v0,1 << 4 | 0x80 >> 3"), [0x60, 0x10 | 0x10]);
+        assert_eq!(rom("mov v0,0x0f ^ 0xff & 0x3c"), [0x60, 0x33]);
+        assert_eq!(rom("mov v0,~0x0f & 0xff"), [0x60, 0xf0]);
+        assert_eq!(rom("mov v0,'A' + 1"), [0x60, 0x42]);
+        assert_eq!(rom("drw v0,v1,16 - 1"), [0xd0, 0x1f]);
+    }
+
+    #[test]
+    fn names() {
+        let rom = rom("\
+const WIDTH = 2
+mov  i,tiles + WIDTH * 1
+jmp  end - 2
+end:
+$tiles 1 2 3 4
+");
+        assert_eq!(rom, [0xa2, 0x06, 0x12, 0x02, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn constants_by_name() {
+        // `jmp`, `call` and `mov i` take these as labels and sprites at first
+        let source = "\
+jmp  START
+call START
+mov  i,START
+START equ 0x300
+";
+        assert_eq!(rom(source), [0x13, 0x00, 0x23, 0x00, 0xa3, 0x00]);
+
+        let options = Options {
+            target: Target::XoChip,
+            ..Options::default()
+        };
+        let assembly = assemble_with("const FAR = 0x1234\nmov i,long FAR\n", &options).unwrap();
+        assert_eq!(assembly.rom, [0xf0, 0x00, 0x12, 0x34]);
+        assert_eq!(
+            assembly.program.statements[1].node,
+            MoveIRegisterLong(0x1234)
+        );
+    }
+
+    #[test]
+    fn names_of_the_other_kind() {
+        // a label for `mov i` and a sprite for `jmp`, bare or not
+        let source = "\
+start:
+    mov  i,start
+    mov  i,start + 0
+    jmp  tiles
+    call tiles + 0
+$tiles 0xff
+";
+        assert_eq!(
+            rom(source),
+            [0xa2, 0x00, 0xa2, 0x00, 0x12, 0x08, 0x22, 0x08, 0xff]
+        );
+        assert_eq!(errors("mov i,nowhere"), [codes::UNDECLARED_SPRITE]);
+        assert_eq!(errors("jmp nowhere"), [codes::UNDECLARED_LABEL]);
+    }
+
+    #[test]
+    fn constants_of_constants() {
+        let source = "\
+const B = A * 2
+const A = 3
+mov v0,B
+";
+        assert_eq!(rom(source), [0x60, 6]);
+        assert_eq!(assemble(source).unwrap().constants["B"], 6);
+    }
+
+    #[test]
+    fn overflow() {
+        assert_eq!(errors("mov v0,1 - 2"), [codes::OVERFLOW]);
+        assert_eq!(errors("const A = 0xffff + 1"), [codes::OVERFLOW]);
+        assert_eq!(errors("const A = 0x100 * 0x100"), [codes::OVERFLOW]);
+        assert_eq!(errors("const A = 1 << 16"), [codes::OVERFLOW]);
+        assert_eq!(rom("mov v0,1 >> 16"), [0x60, 0]);
+    }
+
+    #[test]
+    fn divide_by_zero() {
+        assert_eq!(errors("mov v0,1 / 0"), [codes::DIVIDE_BY_ZERO]);
+        assert_eq!(errors("const Z = 0\nmov v0,1 % Z"), [codes::DIVIDE_BY_ZERO]);
+    }
+
+    #[test]
+    fn circular_constants() {
+        assert_eq!(errors("const A = A"), [codes::CIRCULAR_CONSTANT]);
+        let diagnostics = diagnostics("const A = B + 1\nconst B = A\nmov v0,A");
+        assert!(diagnostics
+            .iter()
+            .all(|d| d.code == Some(codes::CIRCULAR_CONSTANT)));
+        assert!(diagnostics[0].message.contains("A = B = A"));
+    }
+
+    #[test]
+    fn out_of_range() {
+        let diagnostics = diagnostics("const BIG = 300\nmov v0,BIG");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, Some(codes::OPERAND_OUT_OF_RANGE));
+        assert_eq!(diagnostics[0].labels[0].message, "BIG is 300 here");
+
+        assert_eq!(errors("jmp 0xfff + 1"), [codes::OPERAND_OUT_OF_RANGE]);
+    }
+
+    #[test]
+    fn undeclared_and_clashing_names() {
+        assert_eq!(errors("mov v0,HEIGHT"), [codes::UNDECLARED_NAME]);
+        assert_eq!(errors("loop:\nconst loop = 1"), [codes::NAME_CLASH]);
+
+        let assembly = assemble("const A = 1\nconst A = 2\nmov v0,A").unwrap();
+        let codes: Vec<_> = assembly.diagnostics.iter().filter_map(|d| d.code).collect();
+        assert_eq!(codes, [codes::DUPLICATE_CONSTANT]);
+    }
+
+    #[test]
+    fn sprite_bytes() {
+        let source = "\
+const ROW = 0xff
+mov i,box
+$box ROW ROW - 0x7e (1 << 7) | 1 ROW
+";
+        assert_eq!(rom(source), [0xa2, 0x02, 0xff, 0x81, 0x81, 0xff]);
+
+        let diagnostics = diagnostics("const ROW = 0x100\n$s 1 ROW 2");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, Some(codes::OPERAND_OUT_OF_RANGE));
+        assert_eq!(diagnostics[0].labels[0].message, "ROW is 256 here");
+    }
+
+    #[test]
+    fn deferred_checks() {
+        // a `drw` with 0 rows needs schip, whether or not it's written as 0
+        assert_eq!(
+            errors("const ROWS = 0\ndrw v0,v1,ROWS"),
+            [codes::UNSUPPORTED_INSTRUCTION]
+        );
+
+        let options = Options {
+            quirks: Profile::Chip48,
+            ..Options::default()
+        };
+        let assembly = assemble_with("const T = 0x300\njmpr T\n", &options).unwrap();
+        let codes: Vec<_> = assembly.diagnostics.iter().filter_map(|d| d.code).collect();
+        assert_eq!(codes, [codes::QUIRK]);
     }
 }
@@ -0,0 +1,94 @@
+//! Just enough JSON writing for chirp's machine-readable outputs.
+
+use std::fmt::Write;
+
+/// Quotes and escapes `s` as a JSON string.
+pub fn string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+/// Builds a JSON object from already-encoded values, keeping field order.
+#[derive(Default)]
+pub struct Object {
+    fields: Vec<(&'static str, String)>,
+}
+
+impl Object {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn field(mut self, key: &'static str, value: impl Into<String>) -> Self {
+        self.fields.push((key, value.into()));
+        self
+    }
+
+    pub fn str(self, key: &'static str, value: &str) -> Self {
+        self.field(key, string(value))
+    }
+
+    pub fn num(self, key: &'static str, value: impl ToString) -> Self {
+        self.field(key, value.to_string())
+    }
+
+    pub fn build(self) -> String {
+        let fields: Vec<String> = self
+            .fields
+            .into_iter()
+            .map(|(key, value)| format!("{}:{}", string(key), value))
+            .collect();
+
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+pub fn array(values: impl IntoIterator<Item = String>) -> String {
+    format!("[{}]", values.into_iter().collect::<Vec<_>>().join(","))
+}
+
+pub const NULL: &str = "null";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaping() {
+        assert_eq!(string("plain"), r#""plain""#);
+        assert_eq!(string(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(string(r"a\b"), r#""a\\b""#);
+        assert_eq!(string("a\nb\r\tc"), r#""a\nb\r\tc""#);
+        assert_eq!(string("\u{1}\u{1f}"), r#""\u0001\u001f""#);
+        assert_eq!(string("h\u{e9}"), "\"h\u{e9}\"");
+    }
+
+    #[test]
+    fn objects_and_arrays() {
+        let object = Object::new()
+            .str("name", "a")
+            .num("size", 2)
+            .field("items", array(["1".to_string(), NULL.to_string()]))
+            .build();
+        assert_eq!(object, r#"{"name":"a","size":2,"items":[1,null]}"#);
+        assert_eq!(Object::new().build(), "{}");
+        assert_eq!(array(Vec::new()), "[]");
+    }
+}
@@ -1,5 +1,7 @@
 use plex::lexer;
 
+use crate::diagnostic::{codes, Diagnostic};
+
 #[rustfmt::skip]
 #[derive(Debug)]
 pub enum Token {
@@ -9,6 +11,13 @@ pub enum Token {
     Comma,
     Colon,
     Dollar,
+    Minus,
+    Equals,
+    Plus, Star, Slash,
+    Percent, Ampersand, Pipe,
+    Caret, Tilde,
+    LeftShift, RightShift,
+    LeftParen, RightParen,
 
     Nop, Cls,
     Ret, Jmp, Call,
@@ -19,6 +28,16 @@ pub enum Token {
     Rnd, Drw, Skp,
     Sknp, Wait, Spr,
     Bcd, Save, Load,
+    Dw,
+
+    Scd, Scr, Scl,
+    Exit, Low, High,
+    Hspr, Savef, Loadf,
+
+    Long, Plane, Audio,
+    Pitch, Scu,
+
+    Const, Equ,
 
     Register(u8),
     Int8(u8),
@@ -28,17 +47,147 @@ pub enum Token {
     SoundTimer,
 
     Ident(String),
+
+    Error(LexError),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum LexError {
+    InvalidCharacter,
+    /// Holds the radix the literal was written in.
+    MalformedInteger(u32),
+    IntegerOutOfRange,
+    InvalidRegister,
+}
+
+impl LexError {
+    fn diagnostic(self, text: &str, span: Span) -> Diagnostic {
+        match self {
+            LexError::InvalidCharacter => {
+                Diagnostic::error(span, format!("invalid character {:?}", text))
+                    .with_code(codes::INVALID_CHARACTER)
+            }
+            LexError::MalformedInteger(radix) => {
+                let kind = match radix {
+                    16 => "hexadecimal",
+                    2 => "binary",
+                    _ => "decimal",
+                };
+                Diagnostic::error(span, format!("malformed {} integer `{}`", kind, text))
+                    .with_code(codes::MALFORMED_INTEGER)
+            }
+            LexError::IntegerOutOfRange => {
+                Diagnostic::error(span, format!("integer `{}` does not fit in 16 bits", text))
+                    .with_code(codes::INTEGER_OUT_OF_RANGE)
+            }
+            LexError::InvalidRegister => Diagnostic::error(
+                span,
+                format!(
+                    "register `{}` does not exist, registers go from v0 to v15",
+                    text
+                ),
+            )
+            .with_code(codes::INVALID_REGISTER),
+        }
+    }
+}
+
+impl Token {
+    /// How the token reads in a diagnostic, e.g. "register `v3`".
+    pub fn describe(&self) -> String {
+        match self {
+            Token::Newline => "end of line".to_string(),
+            Token::Register(x) => format!("register `v{}`", x),
+            Token::Int8(int) => format!("integer `{}`", int),
+            Token::Int16(int) => format!("integer `{}`", int),
+            Token::Ident(id) => format!("identifier `{}`", id),
+            Token::Error(_) => "invalid token".to_string(),
+            tok => describe_name(&format!("{:?}", tok)),
+        }
+    }
+
+    /// Whether the token is a keyword that came after the base instruction
+    /// set. Programs written before it may use the word as a name, so the
+    /// parser only takes it as a keyword where it can go.
+    pub fn is_contextual(&self) -> bool {
+        matches!(
+            self,
+            Token::Dw
+                | Token::Scd
+                | Token::Scr
+                | Token::Scl
+                | Token::Exit
+                | Token::Low
+                | Token::High
+                | Token::Hspr
+                | Token::Savef
+                | Token::Loadf
+                | Token::Long
+                | Token::Plane
+                | Token::Audio
+                | Token::Pitch
+                | Token::Scu
+                | Token::Const
+                | Token::Equ
+        )
+    }
+}
+
+/// Maps a `Token` variant name, as listed in the parser's expected set, to
+/// the text a user would write.
+pub fn describe_name(name: &str) -> String {
+    match name {
+        "Newline" => "end of line".to_string(),
+        "Comma" => "`,`".to_string(),
+        "Colon" => "`:`".to_string(),
+        "Dollar" => "`$`".to_string(),
+        "Minus" => "`-`".to_string(),
+        "Equals" => "`=`".to_string(),
+        "Plus" => "`+`".to_string(),
+        "Star" => "`*`".to_string(),
+        "Slash" => "`/`".to_string(),
+        "Percent" => "`%`".to_string(),
+        "Ampersand" => "`&`".to_string(),
+        "Pipe" => "`|`".to_string(),
+        "Caret" => "`^`".to_string(),
+        "Tilde" => "`~`".to_string(),
+        "LeftShift" => "`<<`".to_string(),
+        "RightShift" => "`>>`".to_string(),
+        "LeftParen" => "`(`".to_string(),
+        "RightParen" => "`)`".to_string(),
+        "Register" => "register".to_string(),
+        "Int8" | "Int16" => "integer".to_string(),
+        "Ident" => "identifier".to_string(),
+        "IRegister" => "`i`".to_string(),
+        "DelayTimer" => "`dt`".to_string(),
+        "SoundTimer" => "`st`".to_string(),
+        keyword => format!("`{}`", keyword.to_lowercase()),
+    }
 }
 
 lexer! {
     fn next_token(tok: 'a) -> Token;
 
-    r#"[ \r\t]+"# => Token::Whitespace,
-    r#"\n"# => Token::Newline,
-    r#";[^\n]*"# => Token::Comment,
+    r#"[ \t]+"# => Token::Whitespace,
+    r#"\r\n|\n|\r"# => Token::Newline,
+    r#";[^\r\n]*"# => Token::Comment,
     r#","# => Token::Comma,
     r#":"# => Token::Colon,
     r#"$"# => Token::Dollar,
+    r#"-"# => Token::Minus,
+    r#"="# => Token::Equals,
+    r#"\+"# => Token::Plus,
+    r#"\*"# => Token::Star,
+    r#"/"# => Token::Slash,
+    r#"%"# => Token::Percent,
+    r#"\&"# => Token::Ampersand,
+    r#"\|"# => Token::Pipe,
+    r#"\^"# => Token::Caret,
+    r#"\~"# => Token::Tilde,
+    r#"<<"# => Token::LeftShift,
+    r#">>"# => Token::RightShift,
+    r#"\("# => Token::LeftParen,
+    r#"\)"# => Token::RightParen,
 
     r#"nop"#  => Token::Nop,
     r#"cls"#  => Token::Cls,
@@ -66,54 +215,69 @@ lexer! {
     r#"bcd"#  => Token::Bcd,
     r#"save"# => Token::Save,
     r#"load"# => Token::Load,
+    r#"dw"#   => Token::Dw,
 
-    r#"v[0-9][0-5]?"# => {
-        let idx: u8 = tok.trim_matches('v').parse()
-            .expect("could not parse register idx");
-        Token::Register(idx)
-    },
-
-    r#"[0-9]+"# => {
-        let int: u16 = tok.parse()
-            .expect("could not parse dec");
+    r#"scd"#   => Token::Scd,
+    r#"scr"#   => Token::Scr,
+    r#"scl"#   => Token::Scl,
+    r#"exit"#  => Token::Exit,
+    r#"low"#   => Token::Low,
+    r#"high"#  => Token::High,
+    r#"hspr"#  => Token::Hspr,
+    r#"savef"# => Token::Savef,
+    r#"loadf"# => Token::Loadf,
 
-        match int {
-            0..=255 => Token::Int8(int as u8),
-            0..=u16::MAX => Token::Int16(int)
-        }
-    },
+    r#"long"#  => Token::Long,
+    r#"plane"# => Token::Plane,
+    r#"audio"# => Token::Audio,
+    r#"pitch"# => Token::Pitch,
+    r#"scu"#   => Token::Scu,
 
-    r#"0x[0-9a-fA-F]+"# => {
-        let int = u16::from_str_radix(tok.trim_start_matches("0x"), 16)
-            .expect("could not parse hex");
+    r#"const"# => Token::Const,
+    r#"equ"#   => Token::Equ,
 
-        match int {
-            0..=255 => Token::Int8(int as u8),
-            0..=u16::MAX => Token::Int16(int)
-        }
+    r#"v[0-9]+"# => match tok[1..].parse::<u8>() {
+        Ok(idx @ 0..=15) => Token::Register(idx),
+        _ => Token::Error(LexError::InvalidRegister),
     },
 
-    r#"0b[0-1]+"# => {
-        let int = u16::from_str_radix(tok.trim_start_matches("0b"), 2)
-            .expect("could not parse binary");
-
-        match int {
-            0..=255 => Token::Int8(int as u8),
-            0..=u16::MAX => Token::Int16(int)
-        }
+    // the prefixed rules come first so they win ties with the decimal rule,
+    // and all of them swallow trailing letters so `12ab` is one bad literal
+    r#"0x[0-9a-zA-Z_]*"# => integer(&tok[2..], 16),
+    r#"0b[0-9a-zA-Z_]*"# => integer(&tok[2..], 2),
+    r#"[0-9][0-9a-zA-Z_]*"# => integer(tok, 10),
+    // a character stands for its ASCII code
+    r#"'[^'\r\n]'"# => match tok[1..tok.len() - 1].chars().next() {
+        Some(c) if c.is_ascii() => Token::Int8(c as u8),
+        _ => Token::Error(LexError::InvalidCharacter),
     },
 
     r#"i"# => Token::IRegister,
     r#"dt"# => Token::DelayTimer,
     r#"st"# => Token::SoundTimer,
-    r#"[a-zA-Z_]+"# => Token::Ident(tok.to_string()),
+    r#"[a-zA-Z_][a-zA-Z0-9_]*"# => Token::Ident(tok.to_string()),
 
-    r#"."# => panic!("invalid character: {:?}", tok)
+    r#"."# => Token::Error(LexError::InvalidCharacter)
+}
+
+fn integer(digits: &str, radix: u32) -> Token {
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return Token::Error(LexError::MalformedInteger(radix));
+    }
+
+    match u16::from_str_radix(digits, radix) {
+        Ok(int @ 0..=255) => Token::Int8(int as u8),
+        Ok(int) => Token::Int16(int),
+        Err(_) => Token::Error(LexError::IntegerOutOfRange),
+    }
 }
 
 pub struct Lexer<'a> {
     original: &'a str,
     remaining: &'a str,
+    /// Problems found so far. Bad input is still yielded as `Token::Error`
+    /// so the parser sees where it was.
+    pub diagnostics: Vec<Diagnostic>,
 }
 
 impl<'a> Lexer<'a> {
@@ -121,11 +285,12 @@ impl<'a> Lexer<'a> {
         Lexer {
             original: s,
             remaining: s,
+            diagnostics: vec![],
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Span {
     pub lo: usize,
     pub hi: usize,
@@ -148,6 +313,11 @@ impl<'a> Iterator for Lexer<'a> {
                 Token::Whitespace | Token::Comment => {
                     continue;
                 }
+                Token::Error(err) => {
+                    let text = &self.original[span.lo..span.hi];
+                    self.diagnostics.push(err.diagnostic(text, span));
+                    return Some((tok, span));
+                }
                 tok => {
                     return Some((tok, span));
                 }
@@ -155,3 +325,92 @@ impl<'a> Iterator for Lexer<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every token in `source` as the text it covers, with the code of the
+    /// diagnostic reported for it if any.
+    fn lex(source: &str) -> Vec<(&str, Option<&'static str>)> {
+        let mut lexer = Lexer::new(source);
+        let tokens: Vec<(Token, Span)> = lexer.by_ref().collect();
+        let mut diagnostics = lexer.diagnostics.iter();
+        tokens
+            .iter()
+            .map(|(token, span)| {
+                let code = match token {
+                    Token::Error(_) => diagnostics.next().and_then(|d| d.code),
+                    _ => None,
+                };
+                (&source[span.lo..span.hi], code)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn registers() {
+        assert!(matches!(
+            Lexer::new("v15").next(),
+            Some((Token::Register(15), _))
+        ));
+        assert_eq!(
+            lex("v16 v19 v100"),
+            [
+                ("v16", Some(codes::INVALID_REGISTER)),
+                ("v19", Some(codes::INVALID_REGISTER)),
+                ("v100", Some(codes::INVALID_REGISTER)),
+            ]
+        );
+    }
+
+    #[test]
+    fn integers() {
+        assert!(matches!(
+            Lexer::new("0xffff").next(),
+            Some((Token::Int16(0xffff), _))
+        ));
+        assert_eq!(
+            lex("0x10000 70000 0x 0b102 12ab 0b"),
+            [
+                ("0x10000", Some(codes::INTEGER_OUT_OF_RANGE)),
+                ("70000", Some(codes::INTEGER_OUT_OF_RANGE)),
+                ("0x", Some(codes::MALFORMED_INTEGER)),
+                ("0b102", Some(codes::MALFORMED_INTEGER)),
+                ("12ab", Some(codes::MALFORMED_INTEGER)),
+                ("0b", Some(codes::MALFORMED_INTEGER)),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_characters() {
+        assert_eq!(
+            lex("mov v0,@\n#\u{e9}"),
+            [
+                ("mov", None),
+                ("v0", None),
+                (",", None),
+                ("@", Some(codes::INVALID_CHARACTER)),
+                ("\n", None),
+                ("#", Some(codes::INVALID_CHARACTER)),
+                ("\u{e9}", Some(codes::INVALID_CHARACTER)),
+            ]
+        );
+    }
+
+    #[test]
+    fn no_panics() {
+        // every byte on its own, and some that run into each other
+        for c in (0..=0x7f)
+            .filter_map(char::from_u32)
+            .chain(['\u{e9}', '\u{1f600}'])
+        {
+            let source = format!("{}{}0x{}v{}", c, c, c, c);
+            let _ = lex(&source);
+        }
+        let _ = lex("'");
+        let _ = lex("''");
+        let _ = lex("0xffffffffffffffffffff 99999999999999999999 v99999999999");
+    }
+}
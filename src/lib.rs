@@ -0,0 +1,72 @@
+//! chirp: chip-8 intermediate representation
+//!
+//! `assemble` turns chirp source into a rom, along with the symbol tables
+//! and statement addresses the assembler worked out on the way.
+//! `disasm::disassemble` goes the other way, and `decompile::decompile`
+//! also recovers the program's structure.
+
+use diagnostic::{has_errors, Diagnostic};
+use lexer::Lexer;
+use parser::parse;
+
+pub mod debug_info;
+pub mod decompile;
+pub mod diagnostic;
+pub mod disasm;
+pub mod emu;
+mod interp;
+mod json;
+pub mod lexer;
+pub mod parser;
+pub mod quirks;
+pub mod source_map;
+pub mod target;
+mod validate;
+pub mod xref;
+
+pub use interp::{Assembly, MEMORY_SIZE, PROGRAM_START};
+use quirks::Profile;
+use target::Target;
+
+/// How `assemble_with` should assemble.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Code that behaves differently under this profile gets a warning.
+    pub quirks: Profile,
+    /// Instructions the target doesn't have are errors.
+    pub target: Target,
+}
+
+pub fn assemble(source: &str) -> Result<Assembly, Vec<Diagnostic>> {
+    assemble_with(source, &Options::default())
+}
+
+pub fn assemble_with(source: &str, options: &Options) -> Result<Assembly, Vec<Diagnostic>> {
+    let mut lexer = Lexer::new(source);
+    let (program, mut diagnostics) = parse(&mut lexer, source.len());
+    diagnostics.append(&mut lexer.diagnostics);
+    diagnostics.append(&mut validate::validate(&program));
+    diagnostics.append(&mut validate::quirks(&program, options.quirks));
+    diagnostics.append(&mut validate::target(&program, options.target));
+
+    // semantic errors on the lines that did parse are still worth reporting
+    let assembly = match interp::interp(program, options) {
+        Ok(mut assembly) => {
+            diagnostics.append(&mut assembly.diagnostics);
+            Some(assembly)
+        }
+        Err(mut errors) => {
+            diagnostics.append(&mut errors);
+            None
+        }
+    };
+    diagnostics.sort_by_key(|d| d.span.lo);
+
+    match assembly {
+        Some(mut assembly) if !has_errors(&diagnostics) => {
+            assembly.diagnostics = diagnostics;
+            Ok(assembly)
+        }
+        _ => Err(diagnostics),
+    }
+}
@@ -1,39 +1,455 @@
-use interp::interp;
-use lexer::Lexer;
-use parser::parse;
-use std::{env, fs};
+use chirp::{
+    assemble_with,
+    debug_info::{self, SymbolFormat},
+    decompile,
+    diagnostic::{MessageFormat, Reporter},
+    disasm,
+    emu::{self, Machine},
+    lexer::{Lexer, Span},
+    parser,
+    quirks::Profile,
+    source_map::SourceMap,
+    target::Target,
+    xref, Assembly, Options,
+};
+use std::{env, fs, process::exit};
 
-use crate::diagnostic::Diagnostic;
+mod debugger;
+mod player;
 
-mod diagnostic;
-mod interp;
-mod lexer;
-mod parser;
+use player::{Keymap, DEFAULT_KEYS};
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    let input_path = &args.get(1).expect("expected input path");
-    let output_path = &args.get(2).expect("expected output path");
+const USAGE: &str = "usage:
+    chirp [--message-format=human|json] [--target=<target>] [--quirks=<profile>]
+          [--source-map[=<path>]]
+          [--symbols[=<path>]] [--symbol-format=text|octo] [--listing[=<path>]]
+          [--memory-map[=<path>]] <input> <output>
+    chirp disassemble [--entry=<address>]... <input.ch8> [output]
+    chirp decompile [--entry=<address>]... <input.ch8> [output]
+    chirp run [--target=<target>] [--quirks=<profile>] [--keys=<keys>] [--speed=<n>] <input>
+    chirp debug [--target=<target>] [--quirks=<profile>] [--speed=<n>] <input>
+    chirp xref <input> [name]
+
+targets: chip8, eti660, hires, schip, xochip
+quirks profiles: cosmac-vip, chip-48, schip-1.1, xo-chip, modern
+keys: 16 characters for the keypad rows 123c 456d 789e a0bf, 1234qwerasdfzxcv by default";
+
+/// Options that always take a value, so `--name value` works as well as
+/// `--name=value`.
+const VALUE_OPTIONS: [&str; 7] = [
+    "message-format",
+    "target",
+    "quirks",
+    "entry",
+    "keys",
+    "speed",
+    "symbol-format",
+];
+
+/// Positional arguments and `--name[=value]` options, in the order given.
+struct Args {
+    positional: Vec<String>,
+    options: Vec<(String, Option<String>)>,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Self {
+        let mut positional = vec![];
+        let mut options = vec![];
+
+        while let Some(arg) = args.next() {
+            match arg.strip_prefix("--") {
+                Some(option) => match option.split_once('=') {
+                    Some((name, value)) => {
+                        options.push((name.to_string(), Some(value.to_string())))
+                    }
+                    None if VALUE_OPTIONS.contains(&option) => {
+                        options.push((option.to_string(), args.next()))
+                    }
+                    None => options.push((option.to_string(), None)),
+                },
+                None => positional.push(arg),
+            }
+        }
+
+        Self {
+            positional,
+            options,
+        }
+    }
+
+    /// Removes and returns the value of every `--name=value`.
+    fn values(&mut self, name: &str) -> Result<Vec<String>, String> {
+        let mut values = vec![];
+        let mut error = None;
+
+        self.options.retain(|(option, value)| {
+            if option != name {
+                return true;
+            }
+            match value {
+                Some(value) => values.push(value.clone()),
+                None => error = Some(format!("option --{} expects a value", name)),
+            }
+            false
+        });
 
-    let source = fs::read_to_string(input_path).expect("could not read input");
-    let diagnostic = Diagnostic::from_source(&source);
-    let lexer = Lexer::new(&source);
+        match error {
+            Some(error) => Err(error),
+            None => Ok(values),
+        }
+    }
+
+    /// Removes and returns the last value of `--name=value`.
+    fn value(&mut self, name: &str) -> Result<Option<String>, String> {
+        Ok(self.values(name)?.pop())
+    }
+
+    /// Removes `--name` or `--name=path`, returning the path given or
+    /// `default` when there is none.
+    fn path(&mut self, name: &str, default: impl FnOnce() -> String) -> Option<String> {
+        let index = self
+            .options
+            .iter()
+            .rposition(|(option, _)| option == name)?;
+        let value = self.options.remove(index).1;
+        self.options.retain(|(option, _)| option != name);
+        Some(value.unwrap_or_else(default))
+    }
+
+    fn positional(&mut self, what: &str) -> Result<String, String> {
+        if self.positional.is_empty() {
+            return Err(format!("expected {}", what));
+        }
+        Ok(self.positional.remove(0))
+    }
+
+    fn optional_positional(&mut self) -> Option<String> {
+        if self.positional.is_empty() {
+            return None;
+        }
+        Some(self.positional.remove(0))
+    }
 
-    let program = match parse(lexer) {
-        Ok(p) => p,
-        Err(err) => {
-            let (token, span) = err.0.unwrap();
-            diagnostic.error(span, format!("{} but got {:?}", err.1, token));
-            return;
+    /// Fails on anything the command didn't ask for.
+    fn finish(self) -> Result<(), String> {
+        if let Some((name, _)) = self.options.first() {
+            return Err(format!("unknown option --{}", name));
+        }
+        if let Some(arg) = self.positional.first() {
+            return Err(format!("unexpected argument {:?}", arg));
+        }
+        Ok(())
+    }
+}
+
+fn read(path: &str) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|err| {
+        eprintln!("could not read {}: {}", path, err);
+        exit(1);
+    })
+}
+
+fn read_source(path: &str) -> String {
+    fs::read_to_string(path).unwrap_or_else(|err| {
+        eprintln!("could not read {}: {}", path, err);
+        exit(1);
+    })
+}
+
+fn write(path: &str, contents: impl AsRef<[u8]>) {
+    fs::write(path, contents).unwrap_or_else(|err| {
+        eprintln!("could not write {}: {}", path, err);
+        exit(1);
+    })
+}
+
+/// The `--quirks` and `--target` every assembling command takes.
+fn options(args: &mut Args) -> Result<Options, String> {
+    let quirks = match args.value("quirks")? {
+        Some(name) => {
+            Profile::from_name(&name).ok_or_else(|| format!("unknown quirks profile {:?}", name))?
+        }
+        None => Profile::default(),
+    };
+    let target = match args.value("target")? {
+        Some(name) => {
+            Target::from_name(&name).ok_or_else(|| format!("unknown target {:?}", name))?
+        }
+        None => Target::default(),
+    };
+    Ok(Options { quirks, target })
+}
+
+/// Assembles the file at `input_path`, reporting diagnostics and exiting
+/// when there are errors.
+fn assemble_file(input_path: &str, options: &Options, message_format: MessageFormat) -> Assembly {
+    let source = read_source(input_path);
+    let reporter = Reporter::new(input_path, &source).with_format(message_format);
+
+    let assembly = match assemble_with(&source, options) {
+        Ok(a) => a,
+        Err(diagnostics) => {
+            for diagnostic in &diagnostics {
+                reporter.report(diagnostic);
+            }
+            exit(1);
         }
     };
 
-    let ins = interp(&program, &diagnostic);
-    fs::write(output_path, ins.clone()).expect("could not write output");
+    for diagnostic in &assembly.diagnostics {
+        reporter.report(diagnostic);
+    }
+
+    assembly
+}
+
+fn build(mut args: Args) -> Result<(), String> {
+    let message_format = match args.value("message-format")?.as_deref() {
+        None | Some("human") => MessageFormat::Human,
+        Some("json") => MessageFormat::Json,
+        Some(other) => return Err(format!("unknown message format {:?}", other)),
+    };
+    let options = options(&mut args)?;
+    let input_path = args.positional("input path")?;
+    let output_path = args.positional("output path")?;
+    let source_map_path = args.path("source-map", || format!("{}.map", output_path));
+    let symbols_path = args.path("symbols", || format!("{}.sym", output_path));
+    let listing_path = args.path("listing", || format!("{}.lst", output_path));
+    // printed after the summary, or to stderr with json, unless a path is given
+    let memory_map_path = args.path("memory-map", String::new);
+    let symbol_format = match args.value("symbol-format")?.as_deref() {
+        None | Some("text") => SymbolFormat::Text,
+        Some("octo") => SymbolFormat::Octo,
+        Some(other) => return Err(format!("unknown symbol format {:?}", other)),
+    };
+    args.finish()?;
+
+    let assembly = assemble_file(&input_path, &options, message_format);
+    write(&output_path, &assembly.rom);
+
+    if source_map_path.is_some() || listing_path.is_some() {
+        let source = read_source(&input_path);
+        if let Some(path) = source_map_path {
+            write(
+                &path,
+                debug_info::source_map(&assembly, &input_path, &source),
+            );
+        }
+        if let Some(path) = listing_path {
+            write(&path, debug_info::listing(&assembly, &input_path, &source));
+        }
+    }
+    if let Some(path) = symbols_path {
+        write(&path, debug_info::symbols(&assembly, symbol_format));
+    }
+
+    if message_format == MessageFormat::Human {
+        println!(
+            "successfully compiled {} bytes to {}",
+            assembly.rom.len(),
+            output_path
+        );
+    }
+    match memory_map_path.as_deref() {
+        // stdout is only diagnostics in json mode
+        Some("") if message_format == MessageFormat::Json => {
+            eprint!("{}", debug_info::memory_map(&assembly))
+        }
+        Some("") => print!("\n{}", debug_info::memory_map(&assembly)),
+        Some(path) => write(path, debug_info::memory_map(&assembly)),
+        None => {}
+    }
+
+    Ok(())
+}
+
+fn speed(args: &mut Args) -> Result<usize, String> {
+    match args.value("speed")? {
+        Some(speed) => match speed.parse() {
+            Ok(speed) if speed > 0 => Ok(speed),
+            _ => Err(format!("invalid speed {:?}", speed)),
+        },
+        None => Ok(emu::DEFAULT_SPEED),
+    }
+}
+
+fn machine(assembly: &Assembly, options: &Options, speed: usize) -> Result<Machine, String> {
+    let mut machine = Machine::for_target(&assembly.rom, assembly.target)
+        .map_err(|fault| fault.to_string())?
+        .with_quirks(options.quirks.quirks());
+    machine.speed = speed;
+    Ok(machine)
+}
+
+fn run(mut args: Args) -> Result<(), String> {
+    let options = options(&mut args)?;
+    let keymap = Keymap::parse(&args.value("keys")?.unwrap_or(DEFAULT_KEYS.to_string()))?;
+    let speed = speed(&mut args)?;
+    let input_path = args.positional("input path")?;
+    args.finish()?;
+
+    let assembly = assemble_file(&input_path, &options, MessageFormat::Human);
+    player::play(machine(&assembly, &options, speed)?, &keymap)
+}
+
+fn debug(mut args: Args) -> Result<(), String> {
+    let options = options(&mut args)?;
+    let speed = speed(&mut args)?;
+    let input_path = args.positional("input path")?;
+    args.finish()?;
 
-    println!(
-        "successfully compiled {} bytes to {}",
-        ins.len(),
-        output_path
+    let assembly = assemble_file(&input_path, &options, MessageFormat::Human);
+    let source = read_source(&input_path);
+    debugger::debug(
+        machine(&assembly, &options, speed)?,
+        &assembly,
+        &input_path,
+        &source,
     );
+    Ok(())
+}
+
+fn parse_address(text: &str) -> Result<usize, String> {
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| format!("invalid address {:?}", text))
+}
+
+fn disassemble(mut args: Args, structured: bool) -> Result<(), String> {
+    let entries = args
+        .values("entry")?
+        .iter()
+        .map(|entry| parse_address(entry))
+        .collect::<Result<Vec<_>, _>>()?;
+    let input_path = args.positional("input path")?;
+    let output_path = args.optional_positional();
+    args.finish()?;
+
+    let rom = read(&input_path);
+    let disassembly = if structured {
+        decompile::decompile(&rom, &entries)
+    } else {
+        disasm::disassemble(&rom, &entries)
+    };
+
+    for warning in &disassembly.warnings {
+        eprintln!("warning: {}", warning);
+    }
+
+    match output_path {
+        Some(output_path) => write(&output_path, disassembly.source),
+        None => print!("{}", disassembly.source),
+    }
+
+    Ok(())
+}
+
+fn xref(mut args: Args) -> Result<(), String> {
+    let input_path = args.positional("input path")?;
+    let only = args.optional_positional();
+    args.finish()?;
+
+    let source = read_source(&input_path);
+    let reporter = Reporter::new(&input_path, &source);
+    let source_map = SourceMap::new(&source);
+    let location = |span: Span| {
+        let position = source_map.position(span.lo);
+        format!("{}:{}:{}", input_path, position.line, position.column)
+    };
+
+    // lines that failed to parse are reported and left out
+    let mut lexer = Lexer::new(&source);
+    let (program, mut diagnostics) = parser::parse(&mut lexer, source.len());
+    diagnostics.append(&mut lexer.diagnostics);
+    for diagnostic in &diagnostics {
+        reporter.report(diagnostic);
+    }
+
+    let symbols = xref::cross_reference(&program);
+    let mut unused = 0;
+    let mut undeclared = 0;
+
+    for symbol in &symbols {
+        if only.as_deref().is_some_and(|only| only != symbol.name) {
+            continue;
+        }
+
+        let definitions: Vec<String> = symbol
+            .definitions
+            .iter()
+            .map(|span| location(*span))
+            .collect();
+        let mut heading = format!("{} {}", symbol.kind.name(), symbol.name);
+        if !definitions.is_empty() {
+            heading = format!("{}  {}", heading, definitions.join(", "));
+        }
+        if definitions.is_empty() {
+            undeclared += 1;
+            heading.push_str("  undeclared");
+        } else if symbol.references.is_empty() {
+            unused += 1;
+            heading.push_str("  unused");
+        }
+        println!("{}", heading);
+
+        for span in &symbol.references {
+            let line = source_map.position(span.lo).line;
+            println!(
+                "    {}  {}",
+                location(*span),
+                source_map.line_text(line).trim()
+            );
+        }
+    }
+
+    match only {
+        Some(name) if !symbols.iter().any(|symbol| symbol.name == name) => {
+            return Err(format!("no label or sprite named {:?}", name));
+        }
+        Some(_) => {}
+        None => println!(
+            "\n{} symbols, {} unused, {} undeclared",
+            symbols.len(),
+            unused,
+            undeclared
+        ),
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let mut args = Args::parse(env::args().skip(1));
+
+    let result = match args.positional.first().map(String::as_str) {
+        Some("disassemble") => {
+            args.positional.remove(0);
+            disassemble(args, false)
+        }
+        Some("decompile") => {
+            args.positional.remove(0);
+            disassemble(args, true)
+        }
+        Some("run") => {
+            args.positional.remove(0);
+            run(args)
+        }
+        Some("debug") => {
+            args.positional.remove(0);
+            debug(args)
+        }
+        Some("xref") => {
+            args.positional.remove(0);
+            xref(args)
+        }
+        _ => build(args),
+    };
+
+    if let Err(err) = result {
+        eprintln!("{}\n{}", err, USAGE);
+        exit(2);
+    }
 }
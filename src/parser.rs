@@ -1,14 +1,143 @@
+// plex expands every rule into a closure call
+#![allow(clippy::redundant_closure_call, clippy::ptr_arg)]
+
 use plex::parser;
+use std::fmt;
 
-use crate::lexer::{
-    Span,
-    Token::{self, *},
+use crate::{
+    diagnostic::{codes, Diagnostic},
+    lexer::{
+        describe_name, Span,
+        Token::{self, *},
+    },
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Op::Add => "+",
+            Op::Sub => "-",
+            Op::Mul => "*",
+            Op::Div => "/",
+            Op::Rem => "%",
+            Op::And => "&",
+            Op::Or => "|",
+            Op::Xor => "^",
+            Op::Shl => "<<",
+            Op::Shr => ">>",
+        })
+    }
+}
+
+/// An immediate operand, or the value of a constant, worked out by
+/// `interp` once every name has an address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Int(u16),
+    /// A label, sprite or constant, with the span of the name.
+    Name(String, Span),
+    Not(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    /// Every name in the expression, with its span.
+    pub fn names(&self) -> Vec<(&str, Span)> {
+        match self {
+            Expr::Int(_) => vec![],
+            Expr::Name(id, span) => vec![(id.as_str(), *span)],
+            Expr::Not(a) => a.names(),
+            Expr::Binary(_, a, b) => {
+                let mut names = a.names();
+                names.extend(b.names());
+                names
+            }
+        }
+    }
+}
+
+/// Prints the expression back as chirp source, with every operator that
+/// isn't the outermost in parentheses.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let nested = |expr: &Expr| match expr {
+            Expr::Binary(..) => format!("({})", expr),
+            _ => expr.to_string(),
+        };
+        match self {
+            Expr::Int(int) => write!(f, "{}", int),
+            Expr::Name(id, _) => write!(f, "{}", id),
+            Expr::Not(a) => write!(f, "~{}", nested(a)),
+            Expr::Binary(op, a, b) => write!(f, "{} {} {}", nested(a), op, nested(b)),
+        }
+    }
+}
+
+fn binary(op: Op, a: Expr, b: Expr) -> Expr {
+    Expr::Binary(op, Box::new(a), Box::new(b))
+}
+
+/// A statement with its integer operand filled in when it is written as a
+/// plain number, and otherwise left for `interp` to work out.
+fn immediate(mut node: Stmt_, value: Expr) -> (Stmt_, Vec<(usize, Expr)>) {
+    match value {
+        Expr::Int(int) => {
+            node.set_immediate(0, int);
+            (node, vec![])
+        }
+        value => (node, vec![(0, value)]),
+    }
+}
+
+/// Like `immediate`, but a bare name is a label or sprite, made into a
+/// statement by `named`.
+fn immediate_or_named(
+    node: Stmt_,
+    value: Expr,
+    named: fn(String) -> Stmt_,
+) -> (Stmt_, Vec<(usize, Expr)>) {
+    match value {
+        Expr::Name(id, _) => (named(id), vec![]),
+        value => immediate(node, value),
+    }
+}
+
+/// A sprite with the bytes written as plain numbers filled in, and the
+/// rest left for `interp` to work out.
+fn sprite(id: String, bytes: Vec<Expr>) -> (Stmt_, Vec<(usize, Expr)>) {
+    let mut data = vec![];
+    let mut values = vec![];
+    for (index, byte) in bytes.into_iter().enumerate() {
+        match byte {
+            Expr::Int(int) => data.push(int),
+            byte => {
+                data.push(0);
+                values.push((index, byte));
+            }
+        }
+    }
+    (Stmt_::DeclareSprite(id, data), values)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Stmt_ {
-    DeclareSprite(String, Vec<u8>),
+    DeclareSprite(String, Vec<u16>),
     DeclareLabel(String),
+    DeclareConstant(String, Expr),
     Nop,
     Clear,
     Return,
@@ -16,11 +145,11 @@ pub enum Stmt_ {
     JumpLabel(String),
     CallInteger(u16),
     CallLabel(String),
-    SkipEqualsInteger(u8, u8),
-    SkipNotEqualsInteger(u8, u8),
+    SkipEqualsInteger(u8, u16),
+    SkipNotEqualsInteger(u8, u16),
     SkipEqualsRegister(u8, u8),
-    MoveRegisterInteger(u8, u8),
-    AddRegisterInteger(u8, u8),
+    MoveRegisterInteger(u8, u16),
+    AddRegisterInteger(u8, u16),
     MoveRegisterRegister(u8, u8),
     Or(u8, u8),
     And(u8, u8),
@@ -34,8 +163,8 @@ pub enum Stmt_ {
     MoveIRegisterInteger(u16),
     MoveIRegisterSprite(String),
     JumpRegister(u16),
-    Random(u8, u8),
-    Draw(u8, u8, u8),
+    Random(u8, u16),
+    Draw(u8, u8, u16),
     SkipKeyPressed(u8),
     SkipKeyNotPressed(u8),
     MoveRegisterDelay(u8),
@@ -47,21 +176,160 @@ pub enum Stmt_ {
     Bcd(u8),
     Save(u8),
     Load(u8),
+    /// A raw word, for data that isn't an instruction chirp can write.
+    Word(u16),
+    ScrollDown(u16),
+    ScrollRight,
+    ScrollLeft,
+    Exit,
+    LowRes,
+    HighRes,
+    BigSprite(u8),
+    SaveFlags(u8),
+    LoadFlags(u8),
+    SaveRange(u8, u8),
+    LoadRange(u8, u8),
+    MoveIRegisterLong(u16),
+    MoveIRegisterLongSprite(String),
+    Plane(u16),
+    Audio,
+    Pitch(u8),
+    ScrollUp(u16),
+}
+
+impl Stmt_ {
+    /// Bytes the statement takes up in the rom. Only `mov i,long` is
+    /// longer than one word.
+    pub fn size(&self) -> usize {
+        match self {
+            Stmt_::DeclareLabel(_) | Stmt_::DeclareConstant(..) => 0,
+            Stmt_::DeclareSprite(_, data) => data.len(),
+            Stmt_::MoveIRegisterLong(_) | Stmt_::MoveIRegisterLongSprite(_) => 4,
+            _ => 2,
+        }
+    }
+
+    /// Sets the `index`th integer operand, for statements that have one.
+    /// Only sprites have more than one, a byte each.
+    pub fn set_immediate(&mut self, index: usize, value: u16) {
+        match self {
+            Stmt_::DeclareSprite(_, data) => data[index] = value,
+            Stmt_::JumpInteger(n)
+            | Stmt_::CallInteger(n)
+            | Stmt_::SkipEqualsInteger(_, n)
+            | Stmt_::SkipNotEqualsInteger(_, n)
+            | Stmt_::MoveRegisterInteger(_, n)
+            | Stmt_::AddRegisterInteger(_, n)
+            | Stmt_::MoveIRegisterInteger(n)
+            | Stmt_::JumpRegister(n)
+            | Stmt_::Random(_, n)
+            | Stmt_::Draw(_, _, n)
+            | Stmt_::Word(n)
+            | Stmt_::ScrollDown(n)
+            | Stmt_::MoveIRegisterLong(n)
+            | Stmt_::Plane(n)
+            | Stmt_::ScrollUp(n) => *n = value,
+            _ => {}
+        }
+    }
+}
+
+/// Prints the statement back as chirp source.
+impl fmt::Display for Stmt_ {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stmt_::DeclareSprite(id, data) => {
+                write!(f, "${}", id)?;
+                for byte in data {
+                    write!(f, " {:#04x}", byte)?;
+                }
+                Ok(())
+            }
+            Stmt_::DeclareLabel(id) => write!(f, "{}:", id),
+            Stmt_::DeclareConstant(id, value) => write!(f, "const {} = {}", id, value),
+            Stmt_::Nop => write!(f, "nop"),
+            Stmt_::Clear => write!(f, "cls"),
+            Stmt_::Return => write!(f, "ret"),
+            Stmt_::JumpInteger(nnn) => write!(f, "jmp  {:#05x}", nnn),
+            Stmt_::JumpLabel(id) => write!(f, "jmp  {}", id),
+            Stmt_::CallInteger(nnn) => write!(f, "call {:#05x}", nnn),
+            Stmt_::CallLabel(id) => write!(f, "call {}", id),
+            Stmt_::SkipEqualsInteger(x, nn) => write!(f, "se   v{},{:#04x}", x, nn),
+            Stmt_::SkipNotEqualsInteger(x, nn) => write!(f, "sne  v{},{:#04x}", x, nn),
+            Stmt_::SkipEqualsRegister(x, y) => write!(f, "se   v{},v{}", x, y),
+            Stmt_::MoveRegisterInteger(x, nn) => write!(f, "mov  v{},{:#04x}", x, nn),
+            Stmt_::AddRegisterInteger(x, nn) => write!(f, "add  v{},{:#04x}", x, nn),
+            Stmt_::MoveRegisterRegister(x, y) => write!(f, "mov  v{},v{}", x, y),
+            Stmt_::Or(x, y) => write!(f, "or   v{},v{}", x, y),
+            Stmt_::And(x, y) => write!(f, "and  v{},v{}", x, y),
+            Stmt_::Xor(x, y) => write!(f, "xor  v{},v{}", x, y),
+            Stmt_::AddRegisterRegister(x, y) => write!(f, "add  v{},v{}", x, y),
+            Stmt_::Subtract(x, y) => write!(f, "sub  v{},v{}", x, y),
+            Stmt_::ShiftRight(x) => write!(f, "shr  v{}", x),
+            Stmt_::SubtractReverse(x, y) => write!(f, "subn v{},v{}", x, y),
+            Stmt_::ShiftLeft(x) => write!(f, "shl  v{}", x),
+            Stmt_::SkipNotEqualsRegister(x, y) => write!(f, "sne  v{},v{}", x, y),
+            Stmt_::MoveIRegisterInteger(nnn) => write!(f, "mov  i,{:#05x}", nnn),
+            Stmt_::MoveIRegisterSprite(id) => write!(f, "mov  i,{}", id),
+            Stmt_::JumpRegister(nnn) => write!(f, "jmpr {:#05x}", nnn),
+            Stmt_::Random(x, nn) => write!(f, "rnd  v{},{:#04x}", x, nn),
+            Stmt_::Draw(x, y, n) => write!(f, "drw  v{},v{},{}", x, y, n),
+            Stmt_::SkipKeyPressed(x) => write!(f, "skp  v{}", x),
+            Stmt_::SkipKeyNotPressed(x) => write!(f, "sknp v{}", x),
+            Stmt_::MoveRegisterDelay(x) => write!(f, "mov  v{},dt", x),
+            Stmt_::WaitKeyPress(x) => write!(f, "wait v{}", x),
+            Stmt_::MoveDelayRegister(x) => write!(f, "mov  dt,v{}", x),
+            Stmt_::MoveSoundRegister(x) => write!(f, "mov  st,v{}", x),
+            Stmt_::AddIRegisterRegister(x) => write!(f, "add  i,v{}", x),
+            Stmt_::Sprite(x) => write!(f, "spr  v{}", x),
+            Stmt_::Bcd(x) => write!(f, "bcd  v{}", x),
+            Stmt_::Save(x) => write!(f, "save v{}", x),
+            Stmt_::Load(x) => write!(f, "load v{}", x),
+            Stmt_::Word(nnnn) => write!(f, "dw   {:#06x}", nnnn),
+            Stmt_::ScrollDown(n) => write!(f, "scd  {}", n),
+            Stmt_::ScrollRight => write!(f, "scr"),
+            Stmt_::ScrollLeft => write!(f, "scl"),
+            Stmt_::Exit => write!(f, "exit"),
+            Stmt_::LowRes => write!(f, "low"),
+            Stmt_::HighRes => write!(f, "high"),
+            Stmt_::BigSprite(x) => write!(f, "hspr v{}", x),
+            Stmt_::SaveFlags(x) => write!(f, "savef v{}", x),
+            Stmt_::LoadFlags(x) => write!(f, "loadf v{}", x),
+            Stmt_::SaveRange(x, y) => write!(f, "save v{}-v{}", x, y),
+            Stmt_::LoadRange(x, y) => write!(f, "load v{}-v{}", x, y),
+            Stmt_::MoveIRegisterLong(nnnn) => write!(f, "mov  i,long {:#06x}", nnnn),
+            Stmt_::MoveIRegisterLongSprite(id) => write!(f, "mov  i,long {}", id),
+            Stmt_::Plane(n) => write!(f, "plane {}", n),
+            Stmt_::Audio => write!(f, "audio"),
+            Stmt_::Pitch(x) => write!(f, "pitch v{}", x),
+            Stmt_::ScrollUp(n) => write!(f, "scu  {}", n),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Stmt {
     pub span: Span,
     pub node: Stmt_,
+    /// Span of each operand as written: the comma-separated parts after the
+    /// mnemonic (past the `long` of `mov i,long`), the name and each byte of
+    /// a sprite declaration, or the name and value of a constant.
+    pub operands: Vec<Span>,
+    /// The integer operands that are more than a number, by their index
+    /// among the statement's integer operands. `node` holds 0 for each until
+    /// `interp` puts its value there.
+    pub values: Vec<(usize, Expr)>,
 }
 
 pub struct Program {
     pub statements: Vec<Stmt>,
 }
 
-// #[allow(clippy::filter_map_next)]
+// the grammar covers a single line; `parse` splits the token stream at
+// newlines so one bad line doesn't take the rest of the file down with it.
+// immediates are parsed at full width and range-checked by `validate`
 parser! {
-    fn parse_(Token, Span);
+    fn parse_line(Token, Span);
 
     (a, b) {
         Span {
@@ -70,215 +338,452 @@ parser! {
         }
     }
 
-    program: Program {
-        statements[s] => Program {
-            statements: s
-        }
+    line: Option<Stmt> {
+        => None,
+        statement[node] => Some(Stmt {
+            span: span!(),
+            node,
+            operands: vec![],
+            values: vec![],
+        }),
+        immediate[(node, values)] => Some(Stmt {
+            span: span!(),
+            node,
+            operands: vec![],
+            values,
+        }),
     }
 
-    statements: Vec<Stmt> {
-        => vec![],
-        statements[mut s] statement[st] Newline => {
-            s.push(st);
-            s
-        },
-        statements[s] Newline => s
+    int: u16 {
+        Int8(int) => int as u16,
+        Int16(int) => int,
+    }
+
+    // operators bind like they do in C
+    value: Expr {
+        value[a] Pipe xor[b] => binary(Op::Or, a, b),
+        xor[a] => a,
+    }
+
+    xor: Expr {
+        xor[a] Caret and[b] => binary(Op::Xor, a, b),
+        and[a] => a,
+    }
+
+    and: Expr {
+        and[a] Ampersand shift[b] => binary(Op::And, a, b),
+        shift[a] => a,
+    }
+
+    shift: Expr {
+        shift[a] LeftShift sum[b] => binary(Op::Shl, a, b),
+        shift[a] RightShift sum[b] => binary(Op::Shr, a, b),
+        sum[a] => a,
+    }
+
+    sum: Expr {
+        sum[a] Plus product[b] => binary(Op::Add, a, b),
+        sum[a] Minus product[b] => binary(Op::Sub, a, b),
+        product[a] => a,
+    }
+
+    product: Expr {
+        product[a] Star unary[b] => binary(Op::Mul, a, b),
+        product[a] Slash unary[b] => binary(Op::Div, a, b),
+        product[a] Percent unary[b] => binary(Op::Rem, a, b),
+        unary[a] => a,
+    }
+
+    unary: Expr {
+        Tilde unary[a] => Expr::Not(Box::new(a)),
+        atom[a] => a,
     }
 
-    hex: Vec<u8> {
+    atom: Expr {
+        int[int] => Expr::Int(int),
+        Ident(id) => Expr::Name(id, span!()),
+        LeftParen value[a] RightParen => a,
+    }
+
+    // a byte ends where the next token can't carry on its expression
+    hex: Vec<Expr> {
         => vec![],
-        hex[mut d] Int8(int) => {
-            d.push(int);
+        hex[mut d] value[byte] => {
+            d.push(byte);
             d
         }
     }
 
-    statement: Stmt {
-        Dollar Ident(id) hex[data] => Stmt {
-            span: span!(),
-            node: Stmt_::DeclareSprite(id, data)
-        },
-        Ident(id) Colon => Stmt {
-            span: span!(),
-            node: Stmt_::DeclareLabel(id),
-        },
-        Nop => Stmt {
-            span: span!(),
-            node: Stmt_::Nop,
-        },
-        Cls => Stmt {
-            span: span!(),
-            node: Stmt_::Clear,
-        },
-        Ret => Stmt {
-            span: span!(),
-            node: Stmt_::Return,
-        },
-        Jmp Int8(int) => Stmt {
-            span: span!(),
-            node: Stmt_::JumpInteger(int as u16),
-        },
-        Jmp Int16(int) => Stmt {
-            span: span!(),
-            node: Stmt_::JumpInteger(int),
-        },
-        Jmp Ident(id) => Stmt {
-            span: span!(),
-            node: Stmt_::JumpLabel(id),
-        },
-        Call Int8(nnn) => Stmt {
-            span: span!(),
-            node: Stmt_::CallInteger(nnn as u16),
-        },
-        Call Int16(nnn) => Stmt {
-            span: span!(),
-            node: Stmt_::CallInteger(nnn),
-        },
-        Call Ident(id) => Stmt {
-            span: span!(),
-            node: Stmt_::CallLabel(id),
-        },
-        Se Register(x) Comma Int8(nn) => Stmt {
-            span: span!(),
-            node: Stmt_::SkipEqualsInteger(x, nn),
-        },
-        Sne Register(x) Comma Int8(nn) => Stmt {
-            span: span!(),
-            node: Stmt_::SkipNotEqualsInteger(x, nn),
-        },
-        Se Register(x) Comma Register(y) => Stmt {
-            span: span!(),
-            node: Stmt_::SkipEqualsRegister(x, y),
-        },
-        Mov Register(x) Comma Int8(nn) => Stmt {
-            span: span!(),
-            node: Stmt_::MoveRegisterInteger(x, nn),
-        },
-        Add Register(x) Comma Int8(nn) => Stmt {
-            span: span!(),
-            node: Stmt_::AddRegisterInteger(x, nn),
-        },
-        Mov Register(x) Comma Register(y) => Stmt {
-            span: span!(),
-            node: Stmt_::MoveRegisterRegister(x, y),
-        },
-        Or Register(x) Comma Register(y) => Stmt {
-            span: span!(),
-            node: Stmt_::Or(x, y),
-        },
-        And Register(x) Comma Register(y) => Stmt {
-            span: span!(),
-            node: Stmt_::And(x, y),
-        },
-        Xor Register(x) Comma Register(y) => Stmt {
-            span: span!(),
-            node: Stmt_::Xor(x, y),
-        },
-        Add Register(x) Comma Register(y) => Stmt {
-            span: span!(),
-            node: Stmt_::AddRegisterRegister(x, y),
-        },
-        Sub Register(x) Comma Register(y) => Stmt {
-            span: span!(),
-            node: Stmt_::Subtract(x, y),
-        },
-        Shr Register(x) => Stmt {
-            span: span!(),
-            node: Stmt_::ShiftRight(x),
-        },
-        Subn Register(x) Comma Register(y) => Stmt {
-            span: span!(),
-            node: Stmt_::SubtractReverse(x, y),
-        },
-        Shl Register(x) => Stmt {
-            span: span!(),
-            node: Stmt_::ShiftLeft(x),
-        },
-        Sne Register(x) Comma Register(y) => Stmt {
-            span: span!(),
-            node: Stmt_::SkipNotEqualsRegister(x, y),
-        },
-        Mov IRegister Comma Int8(nnn) => Stmt {
-            span: span!(),
-            node: Stmt_::MoveIRegisterInteger(nnn as u16)
-        },
-        Mov IRegister Comma Int16(nnn) => Stmt {
-            span: span!(),
-            node: Stmt_::MoveIRegisterInteger(nnn)
-        },
-        Mov IRegister Comma Ident(id) => Stmt {
-            span: span!(),
-            node: Stmt_::MoveIRegisterSprite(id)
-        },
-        Jmpr Int8(nnn) => Stmt {
-            span: span!(),
-            node: Stmt_::JumpRegister(nnn as u16),
-        },
-        Jmpr Int16(nnn) => Stmt {
-            span: span!(),
-            node: Stmt_::JumpRegister(nnn),
-        },
-        Rnd Register(x) Comma Int8(nn) => Stmt {
-            span: span!(),
-            node: Stmt_::Random(x, nn),
-        },
-        Drw Register(x) Comma Register(y) Comma Int8(n) => Stmt {
-            span: span!(),
-            node: Stmt_::Draw(x, y, n),
-        },
-        Skp Register(x) => Stmt {
-            span: span!(),
-            node: Stmt_::SkipKeyPressed(x),
-        },
-        Sknp Register(x) => Stmt {
-            span: span!(),
-            node: Stmt_::SkipKeyNotPressed(x),
-        },
-        Mov Register(x) Comma DelayTimer => Stmt {
-            span: span!(),
-            node: Stmt_::MoveRegisterDelay(x),
-        },
-        Wait Register(x) => Stmt {
-            span: span!(),
-            node: Stmt_::WaitKeyPress(x),
-        },
-        Mov DelayTimer Comma Register(x) => Stmt {
-            span: span!(),
-            node: Stmt_::MoveDelayRegister(x),
-        },
-        Mov SoundTimer Comma Register(x) => Stmt {
-            span: span!(),
-            node: Stmt_::MoveSoundRegister(x),
-        },
-        Add IRegister Comma Register(x) => Stmt {
-            span: span!(),
-            node: Stmt_::AddIRegisterRegister(x),
-        },
-        Spr Register(x) => Stmt {
-            span: span!(),
-            node: Stmt_::Sprite(x),
-        },
-        Bcd Register(x) => Stmt {
-            span: span!(),
-            node: Stmt_::Bcd(x),
-        },
-        Save Register(x) => Stmt {
-            span: span!(),
-            node: Stmt_::Save(x),
-        },
-        Load Register(x) => Stmt {
-            span: span!(),
-            node: Stmt_::Load(x),
-        },
+    statement: Stmt_ {
+        Ident(id) Colon => Stmt_::DeclareLabel(id),
+        Const Ident(id) Equals value[value] => Stmt_::DeclareConstant(id, value),
+        Ident(id) Equ value[value] => Stmt_::DeclareConstant(id, value),
+        Nop => Stmt_::Nop,
+        Cls => Stmt_::Clear,
+        Ret => Stmt_::Return,
+        Se Register(x) Comma Register(y) => Stmt_::SkipEqualsRegister(x, y),
+        Mov Register(x) Comma Register(y) => Stmt_::MoveRegisterRegister(x, y),
+        Or Register(x) Comma Register(y) => Stmt_::Or(x, y),
+        And Register(x) Comma Register(y) => Stmt_::And(x, y),
+        Xor Register(x) Comma Register(y) => Stmt_::Xor(x, y),
+        Add Register(x) Comma Register(y) => Stmt_::AddRegisterRegister(x, y),
+        Sub Register(x) Comma Register(y) => Stmt_::Subtract(x, y),
+        Shr Register(x) => Stmt_::ShiftRight(x),
+        Subn Register(x) Comma Register(y) => Stmt_::SubtractReverse(x, y),
+        Shl Register(x) => Stmt_::ShiftLeft(x),
+        Sne Register(x) Comma Register(y) => Stmt_::SkipNotEqualsRegister(x, y),
+        Skp Register(x) => Stmt_::SkipKeyPressed(x),
+        Sknp Register(x) => Stmt_::SkipKeyNotPressed(x),
+        Mov Register(x) Comma DelayTimer => Stmt_::MoveRegisterDelay(x),
+        Wait Register(x) => Stmt_::WaitKeyPress(x),
+        Mov DelayTimer Comma Register(x) => Stmt_::MoveDelayRegister(x),
+        Mov SoundTimer Comma Register(x) => Stmt_::MoveSoundRegister(x),
+        Add IRegister Comma Register(x) => Stmt_::AddIRegisterRegister(x),
+        Spr Register(x) => Stmt_::Sprite(x),
+        Bcd Register(x) => Stmt_::Bcd(x),
+        Save Register(x) => Stmt_::Save(x),
+        Load Register(x) => Stmt_::Load(x),
+        Scr => Stmt_::ScrollRight,
+        Scl => Stmt_::ScrollLeft,
+        Exit => Stmt_::Exit,
+        Low => Stmt_::LowRes,
+        High => Stmt_::HighRes,
+        Hspr Register(x) => Stmt_::BigSprite(x),
+        Savef Register(x) => Stmt_::SaveFlags(x),
+        Loadf Register(x) => Stmt_::LoadFlags(x),
+        Save Register(x) Minus Register(y) => Stmt_::SaveRange(x, y),
+        Load Register(x) Minus Register(y) => Stmt_::LoadRange(x, y),
+        Audio => Stmt_::Audio,
+        Pitch Register(x) => Stmt_::Pitch(x),
     }
 
-    nop: () {
-        Newline => {}
+    // statements with integer operands, which can be expressions. `jmp`,
+    // `call` and `mov i` take a bare name as a label or sprite, and `interp`
+    // checks whether that name is a constant instead
+    immediate: (Stmt_, Vec<(usize, Expr)>) {
+        Dollar Ident(id) hex[bytes] => sprite(id, bytes),
+        Jmp value[nnn] => immediate_or_named(Stmt_::JumpInteger(0), nnn, Stmt_::JumpLabel),
+        Call value[nnn] => immediate_or_named(Stmt_::CallInteger(0), nnn, Stmt_::CallLabel),
+        Se Register(x) Comma value[nn] => immediate(Stmt_::SkipEqualsInteger(x, 0), nn),
+        Sne Register(x) Comma value[nn] => immediate(Stmt_::SkipNotEqualsInteger(x, 0), nn),
+        Mov Register(x) Comma value[nn] => immediate(Stmt_::MoveRegisterInteger(x, 0), nn),
+        Add Register(x) Comma value[nn] => immediate(Stmt_::AddRegisterInteger(x, 0), nn),
+        Mov IRegister Comma value[nnn] => {
+            immediate_or_named(Stmt_::MoveIRegisterInteger(0), nnn, Stmt_::MoveIRegisterSprite)
+        }
+        Jmpr value[nnn] => immediate(Stmt_::JumpRegister(0), nnn),
+        Dw value[nnnn] => immediate(Stmt_::Word(0), nnnn),
+        Rnd Register(x) Comma value[nn] => immediate(Stmt_::Random(x, 0), nn),
+        Drw Register(x) Comma Register(y) Comma value[n] => immediate(Stmt_::Draw(x, y, 0), n),
+        Scd value[n] => immediate(Stmt_::ScrollDown(0), n),
+        Mov IRegister Comma Long value[nnnn] => immediate_or_named(
+            Stmt_::MoveIRegisterLong(0),
+            nnnn,
+            Stmt_::MoveIRegisterLongSprite,
+        ),
+        Plane value[n] => immediate(Stmt_::Plane(0), n),
+        Scu value[n] => immediate(Stmt_::ScrollUp(0), n),
     }
 }
 
+pub type ParseError = (Option<(Token, Span)>, &'static str);
+
+/// Parses every line it can. Malformed lines are reported and left out of
+/// the returned `Program`, so later phases can still check the rest.
 pub fn parse<I: Iterator<Item = (Token, Span)>>(
-    i: I,
-) -> Result<Program, (Option<(Token, Span)>, &'static str)> {
-    parse_(i)
+    tokens: I,
+    source_len: usize,
+) -> (Program, Vec<Diagnostic>) {
+    let mut statements = vec![];
+    let mut diagnostics = vec![];
+    let mut line = vec![];
+    let mut tokens = tokens.peekable();
+
+    while tokens.peek().is_some() || !line.is_empty() {
+        let end = match tokens.next() {
+            Some((Token::Newline, span)) => span,
+            Some(token) => {
+                line.push(token);
+                continue;
+            }
+            None => Span {
+                lo: source_len,
+                hi: source_len,
+            },
+        };
+
+        // the lexer has already reported bad tokens, and a syntax error on
+        // top of that would only be noise
+        let lexed = !line
+            .iter()
+            .any(|(token, _)| matches!(token, Token::Error(_)));
+
+        contextual_keywords(&mut line);
+        let operands = operand_spans(&line);
+
+        match parse_line(line.drain(..)) {
+            Ok(Some(stmt)) => statements.push(Stmt { operands, ..stmt }),
+            Ok(None) => {}
+            Err(err) if lexed => diagnostics.push(syntax_error(err, end)),
+            Err(_) => {}
+        }
+    }
+
+    (Program { statements }, diagnostics)
+}
+
+/// Turns contextual keywords back into names everywhere but the start of a
+/// statement, `long` anywhere but before the operand of `mov i,` and `equ`
+/// anywhere but after a constant's name, so `exit:`, `jmp high` and
+/// `mov v0,const` mean what they did before these were keywords.
+fn contextual_keywords(line: &mut [(Token, Span)]) {
+    for index in 0..line.len() {
+        let keyword = match (index, &line[index].0, line.get(index + 1)) {
+            (3, Long, Some(_)) => matches!(line[..3], [(Mov, _), (IRegister, _), (Comma, _)]),
+            (_, Long, _) => false,
+            (1, Equ, _) => true,
+            (0, _, Some((Colon, _))) | (0, _, Some((Equ, _))) => false,
+            (0, _, _) => true,
+            _ => false,
+        };
+        let (token, _) = &mut line[index];
+        if token.is_contextual() && !keyword {
+            *token = Ident(format!("{:?}", token).to_lowercase());
+        }
+    }
+}
+
+fn operand_spans(line: &[(Token, Span)]) -> Vec<Span> {
+    match line {
+        [(Token::Dollar, _), (_, name), rest @ ..] => {
+            let mut spans = vec![*name];
+            spans.extend(byte_spans(rest));
+            spans
+        }
+        // the name, then the value
+        [(Token::Const, _), (Token::Ident(_), name), (Token::Equals, _), value @ ..]
+        | [(Token::Ident(_), name), (Token::Equ, _), value @ ..] => {
+            let mut spans = vec![*name];
+            if let ([(_, first), ..], [.., (_, last)]) = (value, value) {
+                spans.push(Span {
+                    lo: first.lo,
+                    hi: last.hi,
+                });
+            }
+            spans
+        }
+        // `long` only says how big `mov i` is, the operand is what follows
+        [_, rest @ ..] => rest
+            .split(|(token, _)| matches!(token, Token::Comma))
+            .map(|group| match group {
+                [(Token::Long, _), rest @ ..] => rest,
+                group => group,
+            })
+            .filter_map(|group| {
+                let (_, first) = group.first()?;
+                let (_, last) = group.last()?;
+                Some(Span {
+                    lo: first.lo,
+                    hi: last.hi,
+                })
+            })
+            .collect(),
+        [] => vec![],
+    }
+}
+
+/// Splits a sprite's bytes the way the grammar does: a new byte starts
+/// where a token that can start one follows one that can end one, outside
+/// parentheses.
+fn byte_spans(tokens: &[(Token, Span)]) -> Vec<Span> {
+    let mut spans: Vec<Span> = vec![];
+    let mut depth = 0usize;
+    let mut ends_byte = false;
+    for (token, span) in tokens {
+        let starts_byte = matches!(token, Int8(_) | Int16(_) | Ident(_) | LeftParen | Tilde);
+        match spans.last_mut() {
+            Some(last) if !(depth == 0 && ends_byte && starts_byte) => last.hi = span.hi,
+            _ => spans.push(*span),
+        }
+        match token {
+            LeftParen => depth += 1,
+            RightParen => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        ends_byte = matches!(token, Int8(_) | Int16(_) | Ident(_) | RightParen);
+    }
+    spans
+}
+
+/// Turns plex's "expected `A`, `B`, or `C`" into an "expected X, found Y"
+/// diagnostic that names tokens the way they are written in source.
+/// `end` is where the line ends, for errors that ran off the end of it.
+fn syntax_error(err: ParseError, end: Span) -> Diagnostic {
+    let (found, message) = err;
+
+    let mut expected: Vec<String> = vec![];
+    let mut names = message.split('`').skip(1).step_by(2).collect::<Vec<_>>();
+    // plex only sees one line at a time, so its end of file is our end of line
+    if message.ends_with("end of file") {
+        names.push("Newline");
+    }
+    for name in names {
+        let description = describe_name(name);
+        if !expected.contains(&description) {
+            expected.push(description);
+        }
+    }
+
+    let expected = match expected.split_last() {
+        Some((last, [])) => last.clone(),
+        Some((last, [first])) => format!("{} or {}", first, last),
+        Some((last, rest)) => format!("{}, or {}", rest.join(", "), last),
+        None => "nothing".to_string(),
+    };
+
+    let (found, span) = match found {
+        Some((token, span)) => (token.describe(), span),
+        None => (
+            "end of line".to_string(),
+            Span {
+                lo: end.lo,
+                hi: end.lo,
+            },
+        ),
+    };
+
+    Diagnostic::error(span, format!("expected {}, found {}", expected, found))
+        .with_code(codes::SYNTAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assemble, assemble_with, lexer::Lexer, source_map::SourceMap, target::Target, Options,
+    };
+
+    #[test]
+    fn recovery() {
+        let source = "\
+cls
+mov v0
+jmp jmp
+ret
+add v1,,2
+mov v0,@
+mov v1,2
+";
+        let mut lexer = Lexer::new(source);
+        let (program, diagnostics) = parse(&mut lexer, source.len());
+        // one diagnostic per bad line, the lexer's for the bad character
+        let source_map = SourceMap::new(source);
+        let lines: Vec<&str> = diagnostics
+            .iter()
+            .map(|d| source_map.line_text(source_map.position(d.span.lo).line))
+            .collect();
+        assert_eq!(lines, ["mov v0", "jmp jmp", "add v1,,2"]);
+        assert!(diagnostics.iter().all(|d| d.code == Some(codes::SYNTAX)));
+        assert_eq!(lexer.diagnostics.len(), 1);
+
+        let nodes: Vec<&Stmt_> = program.statements.iter().map(|stmt| &stmt.node).collect();
+        assert!(matches!(
+            nodes[..],
+            [
+                Stmt_::Clear,
+                Stmt_::Return,
+                Stmt_::MoveRegisterInteger(1, 2)
+            ]
+        ));
+
+        // and the lines that parsed are still checked
+        let Err(diagnostics) = assemble("mov v0\njmp nowhere\n") else {
+            panic!("assembled");
+        };
+        let codes: Vec<_> = diagnostics.iter().filter_map(|d| d.code).collect();
+        assert_eq!(codes, [codes::SYNTAX, codes::UNDECLARED_LABEL]);
+    }
+
+    fn rom(source: &str) -> Vec<u8> {
+        let options = Options {
+            target: Target::Schip,
+            ..Options::default()
+        };
+        match assemble_with(source, &options) {
+            Ok(assembly) => assembly.rom,
+            Err(diagnostics) => panic!("{:?}", diagnostics),
+        }
+    }
+
+    #[test]
+    fn raw_words() {
+        let source = "\
+dw:
+    dw 0x1234
+    dw dw
+";
+        assert_eq!(rom(source), [0x12, 0x34, 0x02, 0x00]);
+    }
+
+    #[test]
+    fn schip_mnemonics_as_names() {
+        let source = "\
+$scd 0xff
+exit:
+    jmp high
+high:
+    high
+    mov i,scd
+    exit
+    jmp exit
+";
+        assert_eq!(
+            rom(source),
+            [0x12, 0x02, 0x00, 0xFF, 0xA2, 0x0A, 0x00, 0xFD, 0x12, 0x00, 0xFF]
+        );
+    }
+
+    #[test]
+    fn constant_keywords_as_names() {
+        let source = "\
+const:
+    mov v0,equ
+    jmp const
+equ equ 5
+const high = equ + 1
+    mov v1,high
+";
+        assert_eq!(rom(source), [0x60, 0x05, 0x12, 0x00, 0x61, 0x06]);
+    }
+
+    #[test]
+    fn xochip_mnemonics_as_names() {
+        let source = "\
+$audio 0x01 0x02
+$plane 0x03
+long:
+    plane 3
+    mov i,long audio
+    audio
+pitch:
+    jmp long
+    call pitch
+    mov i,plane
+";
+        let options = Options {
+            target: Target::XoChip,
+            ..Options::default()
+        };
+        let rom = match assemble_with(source, &options) {
+            Ok(assembly) => assembly.rom,
+            Err(diagnostics) => panic!("{:?}", diagnostics),
+        };
+        assert_eq!(
+            rom,
+            [
+                0xF3, 0x01, 0xF0, 0x00, 0x02, 0x0E, 0xF0, 0x02, 0x12, 0x00, 0x22, 0x08, 0xA2, 0x10,
+                0x01, 0x02, 0x03
+            ]
+        );
+    }
 }
@@ -0,0 +1,227 @@
+//! `chirp run`: plays a rom in the terminal, drawing the display with
+//! half-block characters and reading keys in raw mode.
+
+use std::{
+    fmt::Write as _,
+    io::{self, Read, Write},
+    process::{Command, Stdio},
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use chirp::emu::{Fault, Machine};
+
+/// Keys for the keypad in its physical layout, row by row:
+/// `1 2 3 c`, `4 5 6 d`, `7 8 9 e`, `a 0 b f`.
+pub const DEFAULT_KEYS: &str = "1234qwerasdfzxcv";
+const LAYOUT: [u8; 16] = [
+    0x1, 0x2, 0x3, 0xC, 0x4, 0x5, 0x6, 0xD, 0x7, 0x8, 0x9, 0xE, 0xA, 0x0, 0xB, 0xF,
+];
+
+/// Bytes the player keeps for itself.
+const PAUSE: u8 = b' ';
+const SLOWER: u8 = b'-';
+const FASTER: [u8; 2] = [b'=', b'+'];
+const RESET: [u8; 2] = [0x7F, 0x08];
+const QUIT: [u8; 2] = [0x1B, 0x03];
+
+const FRAME: Duration = Duration::from_nanos(1_000_000_000 / 60);
+/// Terminals only report presses, so a key counts as held for this many
+/// frames after its last press or repeat.
+const KEY_HOLD_FRAMES: u8 = 10;
+
+/// Maps terminal bytes to keypad keys.
+pub struct Keymap([u8; 128]);
+
+impl Keymap {
+    /// Takes 16 characters for the keypad in the layout `DEFAULT_KEYS` uses.
+    pub fn parse(keys: &str) -> Result<Self, String> {
+        let chars: Vec<char> = keys.chars().collect();
+        if chars.len() != 16 {
+            return Err(format!("--keys expects 16 characters, got {}", chars.len()));
+        }
+
+        let mut map = [0xFF; 128];
+        for (c, key) in chars.into_iter().zip(LAYOUT) {
+            if !c.is_ascii_graphic() {
+                return Err(format!("--keys cannot use {:?}", c));
+            }
+            let byte = c.to_ascii_lowercase() as u8;
+            if byte == SLOWER || FASTER.contains(&byte) {
+                return Err(format!("--keys cannot use {:?}, it changes the speed", c));
+            }
+            if map[byte as usize] != 0xFF {
+                return Err(format!("--keys uses {:?} twice", c));
+            }
+            map[byte as usize] = key;
+        }
+
+        Ok(Keymap(map))
+    }
+
+    fn key(&self, byte: u8) -> Option<u8> {
+        let key = *self.0.get(byte.to_ascii_lowercase() as usize)?;
+        (key != 0xFF).then_some(key)
+    }
+}
+
+/// Puts the terminal in raw mode until dropped.
+struct RawMode {
+    saved: String,
+}
+
+impl RawMode {
+    fn enter() -> Result<Self, String> {
+        let saved = stty(&["-g"])?;
+        stty(&["raw", "-echo"])?;
+        print!("\x1b[?1049h\x1b[?25l\x1b[2J");
+        Ok(RawMode {
+            saved: saved.trim().to_string(),
+        })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        let _ = stty(&[&self.saved]);
+    }
+}
+
+fn stty(args: &[&str]) -> Result<String, String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .output()
+        .map_err(|err| format!("could not run stty: {}", err))?;
+    if !output.status.success() {
+        return Err("stdin is not a terminal".to_string());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn spawn_input() -> Receiver<u8> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for byte in io::stdin().lock().bytes() {
+            let Ok(byte) = byte else { break };
+            if sender.send(byte).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// The display as half blocks, two pixel rows per line of text.
+fn render(machine: &Machine, out: &mut String) {
+    for rows in machine.display[..machine.height()].chunks(2) {
+        for x in 0..machine.width() {
+            // every plane shows the same, there are no colours to tell them apart
+            let top = rows[0][x] != 0;
+            let bottom = rows.get(1).is_some_and(|row| row[x] != 0);
+            out.push(match (top, bottom) {
+                (true, true) => '█',
+                (true, false) => '▀',
+                (false, true) => '▄',
+                (false, false) => ' ',
+            });
+        }
+        out.push_str("\r\n");
+    }
+}
+
+/// Runs `machine` until the player quits or it faults.
+pub fn play(mut machine: Machine, keymap: &Keymap) -> Result<(), String> {
+    let raw_mode = RawMode::enter()?;
+    let input = spawn_input();
+    let mut held = [0u8; 16];
+    let mut paused = false;
+    let mut was_playing = false;
+    let mut status_changed = true;
+    let mut fault: Option<Fault> = None;
+    let mut next_frame = Instant::now();
+
+    'running: loop {
+        loop {
+            let byte = match input.try_recv() {
+                Ok(byte) => byte,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => break 'running,
+            };
+            status_changed = true;
+
+            match byte {
+                _ if QUIT.contains(&byte) => break 'running,
+                PAUSE => paused = !paused,
+                SLOWER => machine.speed = machine.speed.saturating_sub(1).max(1),
+                _ if FASTER.contains(&byte) => machine.speed += 1,
+                _ if RESET.contains(&byte) => {
+                    machine.reset();
+                    held = [0; 16];
+                    fault = None;
+                }
+                _ => {
+                    if let Some(key) = keymap.key(byte) {
+                        held[key as usize] = KEY_HOLD_FRAMES;
+                    }
+                }
+            }
+        }
+
+        for (key, frames) in held.iter_mut().enumerate() {
+            machine.set_key(key as u8, *frames > 0);
+            *frames = frames.saturating_sub(1);
+        }
+
+        if !paused && fault.is_none() {
+            if let Err(err) = machine.frame() {
+                fault = Some(err);
+                status_changed = true;
+            }
+        }
+
+        let playing = machine.sound_playing();
+        if playing && !was_playing {
+            print!("\x07");
+        }
+        was_playing = playing;
+
+        if machine.display_changed || status_changed {
+            let mut out = String::from("\x1b[H");
+            render(&machine, &mut out);
+            let state = match (fault, paused) {
+                (Some(fault), _) => format!("stopped: {}", fault),
+                (None, true) => "paused".to_string(),
+                (None, false) => "running".to_string(),
+            };
+            let _ = write!(
+                out,
+                "\x1b[K{} at {} instructions/frame | space pause, -/= speed, backspace reset, esc quit",
+                state, machine.speed
+            );
+            // clears what a high resolution picture left below
+            out.push_str("\x1b[J");
+            print!("{}", out);
+            let _ = io::stdout().flush();
+            machine.display_changed = false;
+            status_changed = false;
+        }
+
+        next_frame += FRAME;
+        let now = Instant::now();
+        if next_frame > now {
+            thread::sleep(next_frame - now);
+        } else {
+            next_frame = now;
+        }
+    }
+
+    drop(raw_mode);
+    if let Some(fault) = fault {
+        eprintln!("stopped: {}", fault);
+    }
+    Ok(())
+}
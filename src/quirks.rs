@@ -0,0 +1,118 @@
+//! Behaviours CHIP-8 interpreters disagree on, and the interpreters that
+//! chose them.
+
+use std::fmt;
+
+/// How far `save` and `load` move I.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryIncrement {
+    None,
+    /// By x, one short of the registers touched, as CHIP-48 did.
+    ByX,
+    /// Past the last register touched.
+    ByXPlusOne,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// `8xy6`/`8xyE` shift vy into vx instead of shifting vx in place.
+    pub shift_uses_vy: bool,
+    pub memory_increment: MemoryIncrement,
+    /// `Bnnn` adds vx, x being the top nibble of nnn, instead of v0.
+    pub jump_uses_vx: bool,
+    /// `or`, `and` and `xor` set VF to 0.
+    pub logic_resets_vf: bool,
+    /// Sprites wrap around the edges of the screen instead of clipping.
+    pub draw_wraps: bool,
+    /// `drw` waits for the next frame, so at most one runs per frame.
+    pub display_wait: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Profile {
+    CosmacVip,
+    Chip48,
+    Schip11,
+    XoChip,
+    /// What most emulators written today do, and what chirp assumes.
+    #[default]
+    Modern,
+}
+
+impl Profile {
+    pub const ALL: [Profile; 5] = [
+        Profile::CosmacVip,
+        Profile::Chip48,
+        Profile::Schip11,
+        Profile::XoChip,
+        Profile::Modern,
+    ];
+
+    /// The name used on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Profile::CosmacVip => "cosmac-vip",
+            Profile::Chip48 => "chip-48",
+            Profile::Schip11 => "schip-1.1",
+            Profile::XoChip => "xo-chip",
+            Profile::Modern => "modern",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Profile> {
+        Profile::ALL
+            .into_iter()
+            .find(|profile| profile.name() == name)
+    }
+
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Profile::CosmacVip => Quirks {
+                shift_uses_vy: true,
+                memory_increment: MemoryIncrement::ByXPlusOne,
+                jump_uses_vx: false,
+                logic_resets_vf: true,
+                draw_wraps: false,
+                display_wait: true,
+            },
+            Profile::Chip48 => Quirks {
+                shift_uses_vy: false,
+                memory_increment: MemoryIncrement::ByX,
+                jump_uses_vx: true,
+                logic_resets_vf: false,
+                draw_wraps: false,
+                display_wait: false,
+            },
+            Profile::Schip11 => Quirks {
+                shift_uses_vy: false,
+                memory_increment: MemoryIncrement::None,
+                jump_uses_vx: true,
+                logic_resets_vf: false,
+                draw_wraps: false,
+                display_wait: true,
+            },
+            Profile::XoChip => Quirks {
+                shift_uses_vy: true,
+                memory_increment: MemoryIncrement::ByXPlusOne,
+                jump_uses_vx: false,
+                logic_resets_vf: false,
+                draw_wraps: true,
+                display_wait: false,
+            },
+            Profile::Modern => Quirks {
+                shift_uses_vy: false,
+                memory_increment: MemoryIncrement::None,
+                jump_uses_vx: false,
+                logic_resets_vf: false,
+                draw_wraps: false,
+                display_wait: false,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
@@ -0,0 +1,178 @@
+use crate::lexer::Span;
+
+/// 1-based line and column of a byte offset. Columns count characters, not
+/// bytes, so they line up with what an editor shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Line-start index over a source file, built once and shared by everything
+/// that needs to turn a `Span` into something a human can find.
+///
+/// `\n`, `\r\n` and a lone `\r` all end a line.
+pub struct SourceMap<'a> {
+    source: &'a str,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> SourceMap<'a> {
+    pub fn new(source: &'a str) -> Self {
+        let bytes = source.as_bytes();
+        let mut line_starts = vec![0];
+        let mut i = 0;
+
+        while i < bytes.len() {
+            match bytes[i] {
+                b'\r' if bytes.get(i + 1) == Some(&b'\n') => {
+                    i += 2;
+                    line_starts.push(i);
+                }
+                b'\r' | b'\n' => {
+                    i += 1;
+                    line_starts.push(i);
+                }
+                _ => i += 1,
+            }
+        }
+
+        Self {
+            source,
+            line_starts,
+        }
+    }
+
+    pub fn source(&self) -> &'a str {
+        self.source
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    /// 0-based index of the line containing `offset`.
+    fn line_index(&self, offset: usize) -> usize {
+        match self.line_starts.binary_search(&offset) {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        }
+    }
+
+    pub fn position(&self, offset: usize) -> Position {
+        let offset = offset.min(self.source.len());
+        let index = self.line_index(offset);
+        let start = self.line_starts[index];
+        let column = self.source[start..offset].chars().count() + 1;
+
+        Position {
+            line: index + 1,
+            column,
+        }
+    }
+
+    pub fn span_positions(&self, span: Span) -> (Position, Position) {
+        (self.position(span.lo), self.position(span.hi))
+    }
+
+    /// Byte offset at which 1-based `line` starts.
+    pub fn line_start(&self, line: usize) -> usize {
+        self.line_starts[line - 1]
+    }
+
+    /// Text of 1-based `line`, without its line ending.
+    pub fn line_text(&self, line: usize) -> &'a str {
+        let start = self.line_starts[line - 1];
+        let end = self
+            .line_starts
+            .get(line)
+            .copied()
+            .unwrap_or(self.source.len());
+
+        self.source[start..end].trim_end_matches(['\r', '\n'])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(line: usize, column: usize) -> Position {
+        Position { line, column }
+    }
+
+    #[test]
+    fn lf() {
+        let map = SourceMap::new("cls\nret\n");
+        assert_eq!(map.line_count(), 3);
+        assert_eq!(map.position(4), at(2, 1));
+        assert_eq!(map.position(6), at(2, 3));
+        assert_eq!(map.line_text(1), "cls");
+        assert_eq!(map.line_text(2), "ret");
+    }
+
+    #[test]
+    fn crlf() {
+        let map = SourceMap::new("cls\r\nret\r\n");
+        assert_eq!(map.line_count(), 3);
+        assert_eq!(map.position(3), at(1, 4));
+        assert_eq!(map.position(5), at(2, 1));
+        assert_eq!(map.line_text(1), "cls");
+        assert_eq!(map.line_text(2), "ret");
+    }
+
+    #[test]
+    fn lone_cr() {
+        let map = SourceMap::new("cls\rret\r");
+        assert_eq!(map.line_count(), 3);
+        assert_eq!(map.position(4), at(2, 1));
+        assert_eq!(map.line_text(1), "cls");
+        assert_eq!(map.line_text(2), "ret");
+    }
+
+    #[test]
+    fn mixed() {
+        let map = SourceMap::new("a\nb\r\nc\rd");
+        assert_eq!(map.line_count(), 4);
+        assert_eq!(map.position(2), at(2, 1));
+        assert_eq!(map.position(5), at(3, 1));
+        assert_eq!(map.position(7), at(4, 1));
+        assert_eq!(map.line_start(3), 5);
+        let lines: Vec<&str> = (1..=4).map(|line| map.line_text(line)).collect();
+        assert_eq!(lines, ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn no_final_newline() {
+        let map = SourceMap::new("cls\nret");
+        assert_eq!(map.line_count(), 2);
+        assert_eq!(map.line_text(2), "ret");
+        assert_eq!(map.position(6), at(2, 3));
+    }
+
+    #[test]
+    fn end_of_file() {
+        let map = SourceMap::new("cls\nret");
+        assert_eq!(map.position(7), at(2, 4));
+        // past the end is clamped to it
+        assert_eq!(map.position(100), at(2, 4));
+
+        let map = SourceMap::new("cls\n");
+        assert_eq!(map.position(4), at(2, 1));
+        assert_eq!(map.line_text(2), "");
+    }
+
+    #[test]
+    fn multibyte() {
+        // `é` is 2 bytes and `日` is 3, but each is one column
+        let map = SourceMap::new("; é\n日 x");
+        assert_eq!(map.position(4), at(1, 4));
+        assert_eq!(map.position(5), at(2, 1));
+        assert_eq!(map.position(9), at(2, 3));
+        assert_eq!(
+            map.span_positions(Span { lo: 9, hi: 10 }),
+            (at(2, 3), at(2, 4))
+        );
+        assert_eq!(map.line_text(2), "日 x");
+    }
+}
@@ -0,0 +1,289 @@
+//! The interpreters chirp can build for: where each loads programs, how
+//! much memory it has, and which instructions it knows.
+
+use std::fmt;
+
+use crate::{
+    parser::Stmt_::{self, *},
+    MEMORY_SIZE, PROGRAM_START,
+};
+
+/// A memory range the program can't use, from `start` up to `end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: usize,
+    pub end: usize,
+    pub name: &'static str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Target {
+    #[default]
+    Chip8,
+    /// CHIP-8 on the ETI-660, which loads programs at 0x600.
+    Eti660,
+    /// The 64x64 hires CHIP-8 for the COSMAC VIP. Roms start with `12 60`,
+    /// which the hires interpreter looks for before running the program
+    /// from 0x244; the bytes in between are its own and left zeroed.
+    Hires,
+    /// SUPER-CHIP 1.1.
+    Schip,
+    XoChip,
+}
+
+impl Target {
+    pub const ALL: [Target; 5] = [
+        Target::Chip8,
+        Target::Eti660,
+        Target::Hires,
+        Target::Schip,
+        Target::XoChip,
+    ];
+
+    /// The name used on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Target::Chip8 => "chip8",
+            Target::Eti660 => "eti660",
+            Target::Hires => "hires",
+            Target::Schip => "schip",
+            Target::XoChip => "xochip",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Target> {
+        Target::ALL.into_iter().find(|target| target.name() == name)
+    }
+
+    /// Where the rom is loaded.
+    pub fn load_address(self) -> usize {
+        match self {
+            Target::Eti660 => 0x600,
+            _ => PROGRAM_START,
+        }
+    }
+
+    /// Bytes every rom starts with, before the program.
+    pub fn prefix(self) -> &'static [u8] {
+        match self {
+            Target::Hires => &[0x12, 0x60],
+            _ => &[],
+        }
+    }
+
+    /// Where the program's first instruction goes and execution starts.
+    pub fn entry(self) -> usize {
+        match self {
+            Target::Hires => 0x244,
+            _ => self.load_address(),
+        }
+    }
+
+    /// Bytes of memory, all of it addressable by `mov i,long` on XO-CHIP.
+    pub fn memory_size(self) -> usize {
+        match self {
+            Target::XoChip => 0x10000,
+            _ => MEMORY_SIZE,
+        }
+    }
+
+    /// What the interpreter keeps for itself, in address order.
+    pub fn reserved(self) -> Vec<Region> {
+        let interpreter = Region {
+            start: 0,
+            end: self.load_address(),
+            name: "interpreter",
+        };
+        match self {
+            Target::Hires => vec![
+                interpreter,
+                Region {
+                    start: self.load_address(),
+                    end: self.entry(),
+                    name: "hires prefix",
+                },
+            ],
+            _ => vec![interpreter],
+        }
+    }
+
+    /// Width and height of the display, before any `high`.
+    pub fn resolution(self) -> (usize, usize) {
+        match self {
+            Target::Eti660 => (64, 48),
+            Target::Hires => (64, 64),
+            _ => (64, 32),
+        }
+    }
+
+    /// The first of chip8, schip and xochip with `stmt`; each has every
+    /// instruction of the ones before it.
+    pub fn introducing(stmt: &Stmt_) -> Target {
+        match *stmt {
+            ScrollDown(_) | ScrollRight | ScrollLeft | Exit | LowRes | HighRes | BigSprite(_)
+            | SaveFlags(_) | LoadFlags(_) => Target::Schip,
+            Draw(_, _, 0) => Target::Schip,
+            SaveRange(..)
+            | LoadRange(..)
+            | MoveIRegisterLong(_)
+            | MoveIRegisterLongSprite(_)
+            | Plane(_)
+            | Audio
+            | Pitch(_)
+            | ScrollUp(_) => Target::XoChip,
+            _ => Target::Chip8,
+        }
+    }
+
+    /// The instruction set this target builds on.
+    fn instruction_set(self) -> Target {
+        match self {
+            Target::Eti660 | Target::Hires => Target::Chip8,
+            target => target,
+        }
+    }
+
+    pub fn supports(self, stmt: &Stmt_) -> bool {
+        Target::introducing(stmt) <= self.instruction_set()
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assemble_with, diagnostic::codes, Options};
+
+    use super::*;
+
+    /// The rom for `source` built for `target`, or the codes of its errors.
+    fn build(source: &str, target: Target) -> Result<Vec<u8>, Vec<&'static str>> {
+        let options = Options {
+            target,
+            ..Options::default()
+        };
+        assemble_with(source, &options)
+            .map(|assembly| assembly.rom)
+            .map_err(|diagnostics| diagnostics.iter().filter_map(|d| d.code).collect())
+    }
+
+    #[test]
+    fn schip_encodings() {
+        let source = "\
+scd 3
+scr
+scl
+exit
+low
+high
+drw v1,v2,0
+hspr v3
+savef v7
+loadf v2
+";
+        assert_eq!(
+            build(source, Target::Schip).unwrap(),
+            [
+                0x00, 0xC3, 0x00, 0xFB, 0x00, 0xFC, 0x00, 0xFD, 0x00, 0xFE, 0x00, 0xFF, 0xD1, 0x20,
+                0xF3, 0x30, 0xF7, 0x75, 0xF2, 0x85,
+            ]
+        );
+        assert_eq!(
+            build(source, Target::Chip8).unwrap_err(),
+            [codes::UNSUPPORTED_INSTRUCTION; 10]
+        );
+    }
+
+    #[test]
+    fn xochip_encodings() {
+        let source = "\
+save v1-v4
+load v4-v1
+plane 2
+audio
+pitch v5
+scu 7
+mov i,long 0x1234
+";
+        assert_eq!(
+            build(source, Target::XoChip).unwrap(),
+            [
+                0x51, 0x42, 0x54, 0x13, 0xF2, 0x01, 0xF0, 0x02, 0xF5, 0x3A, 0x00, 0xD7, 0xF0, 0x00,
+                0x12, 0x34,
+            ]
+        );
+        assert_eq!(
+            build(source, Target::Schip).unwrap_err(),
+            [codes::UNSUPPORTED_INSTRUCTION; 7]
+        );
+    }
+
+    #[test]
+    fn long_loads_move_what_follows() {
+        let source = "\
+$tile 0xff
+mov i,long tile
+after:
+    jmp after
+    mov i,tile
+";
+        let options = Options {
+            target: Target::XoChip,
+            ..Options::default()
+        };
+        let assembly = assemble_with(source, &options).unwrap();
+        assert_eq!(assembly.labels["after"], 0x204);
+        assert_eq!(assembly.sprites["tile"], 0x208);
+        assert_eq!(
+            assembly.rom,
+            [0xF0, 0x00, 0x02, 0x08, 0x12, 0x04, 0xA2, 0x08, 0xFF]
+        );
+    }
+
+    #[test]
+    fn layouts() {
+        let source = "$dot 0x80\nloop:\n    mov i,dot\n    jmp loop\n";
+        let options = |target| Options {
+            target,
+            ..Options::default()
+        };
+
+        let eti660 = assemble_with(source, &options(Target::Eti660)).unwrap();
+        assert_eq!(eti660.labels["loop"], 0x600);
+        assert_eq!(eti660.rom, [0xA6, 0x04, 0x16, 0x00, 0x80]);
+
+        let hires = assemble_with(source, &options(Target::Hires)).unwrap();
+        assert_eq!(hires.labels["loop"], 0x244);
+        assert_eq!(hires.rom[..2], [0x12, 0x60]);
+        assert!(hires.rom[2..0x44].iter().all(|byte| *byte == 0));
+        assert_eq!(hires.rom[0x44..], [0xA2, 0x48, 0x12, 0x44, 0x80]);
+
+        assert_eq!(
+            Target::Hires.reserved(),
+            [
+                Region {
+                    start: 0,
+                    end: 0x200,
+                    name: "interpreter"
+                },
+                Region {
+                    start: 0x200,
+                    end: 0x244,
+                    name: "hires prefix"
+                },
+            ]
+        );
+        // the eti660 has 0x600 bytes less room
+        let full = "cls\n".repeat((0x1000 - 0x600) / 2);
+        assert!(assemble_with(&full, &options(Target::Eti660)).is_ok());
+        let over = format!("{}cls\n", full);
+        assert_eq!(
+            build(&over, Target::Eti660).unwrap_err(),
+            [codes::ROM_TOO_LARGE]
+        );
+    }
+}
@@ -0,0 +1,567 @@
+use crate::{
+    diagnostic::{codes, Diagnostic},
+    lexer::Span,
+    parser::{
+        Program, Stmt,
+        Stmt_::{self, *},
+    },
+    quirks::{MemoryIncrement, Profile},
+    target::Target,
+};
+
+/// How many bits an operand has in its encoding.
+#[derive(Debug, Clone, Copy)]
+pub enum Width {
+    Nibble,
+    Byte,
+    Address,
+}
+
+impl Width {
+    pub fn max(self) -> u16 {
+        match self {
+            Width::Nibble => 0xF,
+            Width::Byte => 0xFF,
+            Width::Address => 0xFFF,
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Width::Nibble => "a nibble (0..=15)",
+            Width::Byte => "a byte (0..=255)",
+            Width::Address => "12 bits (0x000..=0xfff)",
+        }
+    }
+}
+
+/// Checks `value` fits in `width`, reporting against the operand's own span.
+pub fn check(value: u16, width: Width, span: Span) -> Option<Diagnostic> {
+    if value <= width.max() {
+        return None;
+    }
+
+    let value = match width {
+        Width::Address => format!("{:#x}", value),
+        _ => value.to_string(),
+    };
+
+    Some(
+        Diagnostic::error(
+            span,
+            format!("`{}` does not fit in {}", value, width.describe()),
+        )
+        .with_code(codes::OPERAND_OUT_OF_RANGE),
+    )
+}
+
+/// Immediate operands of `stmt` as (operand index, value, width).
+fn immediates(stmt: &Stmt) -> Vec<(usize, u16, Width)> {
+    match stmt.node {
+        DeclareSprite(_, ref data) => data
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| (i + 1, *byte, Width::Byte))
+            .collect(),
+        JumpInteger(nnn) | CallInteger(nnn) | JumpRegister(nnn) => {
+            vec![(0, nnn, Width::Address)]
+        }
+        MoveIRegisterInteger(nnn) => vec![(1, nnn, Width::Address)],
+        SkipEqualsInteger(_, nn)
+        | SkipNotEqualsInteger(_, nn)
+        | MoveRegisterInteger(_, nn)
+        | AddRegisterInteger(_, nn)
+        | Random(_, nn) => vec![(1, nn, Width::Byte)],
+        Draw(_, _, n) => vec![(2, n, Width::Nibble)],
+        ScrollDown(n) | ScrollUp(n) | Plane(n) => vec![(0, n, Width::Nibble)],
+        _ => vec![],
+    }
+}
+
+/// The register `node` writes its result to, as (operand index, register).
+/// `load` restores a whole range and isn't counted.
+pub fn destination(node: &Stmt_) -> Option<(usize, u8)> {
+    match *node {
+        MoveRegisterInteger(x, _)
+        | AddRegisterInteger(x, _)
+        | MoveRegisterRegister(x, _)
+        | Or(x, _)
+        | And(x, _)
+        | Xor(x, _)
+        | AddRegisterRegister(x, _)
+        | Subtract(x, _)
+        | SubtractReverse(x, _)
+        | ShiftRight(x)
+        | ShiftLeft(x)
+        | Random(x, _)
+        | MoveRegisterDelay(x)
+        | WaitKeyPress(x) => Some((0, x)),
+        _ => None,
+    }
+}
+
+/// Checks the operands of `stmt` are in range. `interp` runs this again
+/// once it knows the value of the constants an operand names.
+pub fn operands(stmt: &Stmt) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+
+    for (operand, value, width) in immediates(stmt) {
+        let span = stmt.operands.get(operand).copied().unwrap_or(stmt.span);
+        diagnostics.extend(check(value, width, span));
+    }
+
+    if let SaveFlags(x) | LoadFlags(x) = stmt.node {
+        if x > 7 {
+            let span = stmt.operands.first().copied().unwrap_or(stmt.span);
+            diagnostics.push(
+                Diagnostic::error(
+                    span,
+                    format!("there are only 8 flag registers, v{} is past v7", x),
+                )
+                .with_code(codes::OPERAND_OUT_OF_RANGE),
+            );
+        }
+    }
+
+    if let Plane(n @ 4..=15) = stmt.node {
+        let span = stmt.operands.first().copied().unwrap_or(stmt.span);
+        diagnostics.push(
+            Diagnostic::error(
+                span,
+                format!(
+                    "there are only 2 planes, so the mask goes up to 3, not {}",
+                    n
+                ),
+            )
+            .with_code(codes::OPERAND_OUT_OF_RANGE),
+        );
+    }
+
+    diagnostics
+}
+
+pub fn validate(program: &Program) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+
+    for stmt in &program.statements {
+        diagnostics.extend(operands(stmt));
+
+        if let Some((operand, 0xF)) = destination(&stmt.node) {
+            let span = stmt.operands.get(operand).copied().unwrap_or(stmt.span);
+            diagnostics.push(
+                Diagnostic::error(
+                    span,
+                    "v15 is vf, the flag register, which programs can read but not write to"
+                        .to_string(),
+                )
+                .with_code(codes::INVALID_REGISTER),
+            );
+        }
+    }
+
+    diagnostics.extend(skipped_long_loads(program));
+    diagnostics
+}
+
+fn is_skip(stmt: &Stmt) -> bool {
+    matches!(
+        stmt.node,
+        SkipEqualsInteger(..)
+            | SkipNotEqualsInteger(..)
+            | SkipEqualsRegister(..)
+            | SkipNotEqualsRegister(..)
+            | SkipKeyPressed(_)
+            | SkipKeyNotPressed(_)
+    )
+}
+
+/// Skips right before a 4-byte `mov i,long`, which only interpreters that
+/// know about it skip all of.
+fn skipped_long_loads(program: &Program) -> Vec<Diagnostic> {
+    let instructions: Vec<&Stmt> = program
+        .statements
+        .iter()
+        .filter(|stmt| {
+            !matches!(
+                stmt.node,
+                DeclareLabel(_) | DeclareSprite(..) | DeclareConstant(..)
+            )
+        })
+        .collect();
+
+    instructions
+        .windows(2)
+        .filter(|pair| is_skip(pair[0]) && pair[1].node.size() == 4)
+        .map(|pair| {
+            Diagnostic::warning(
+                pair[0].span,
+                "this skips a 4-byte `mov i,long`, which lands in the middle of it \
+                 on interpreters that only skip 2"
+                    .to_string(),
+            )
+            .with_code(codes::SKIPPED_LONG_LOAD)
+            .with_label(pair[1].span, "4 bytes long".to_string())
+        })
+        .collect()
+}
+
+/// Whether `stmt` reads I without setting it first.
+fn reads_i(stmt: &Stmt) -> bool {
+    matches!(
+        stmt.node,
+        Draw(..)
+            | AddIRegisterRegister(_)
+            | Bcd(_)
+            | Save(_)
+            | Load(_)
+            | SaveRange(..)
+            | LoadRange(..)
+            | Audio
+    )
+}
+
+/// Whether `stmt` points I somewhere new.
+fn writes_i(stmt: &Stmt) -> bool {
+    matches!(
+        stmt.node,
+        MoveIRegisterInteger(_)
+            | MoveIRegisterSprite(_)
+            | MoveIRegisterLong(_)
+            | MoveIRegisterLongSprite(_)
+            | Sprite(_)
+            | BigSprite(_)
+    )
+}
+
+/// Whether control can leave the straight line after `stmt`.
+fn branches(stmt: &Stmt) -> bool {
+    matches!(
+        stmt.node,
+        JumpInteger(_)
+            | JumpLabel(_)
+            | CallInteger(_)
+            | CallLabel(_)
+            | Return
+            | JumpRegister(_)
+            | Exit
+    )
+}
+
+/// Warns about code whose behaviour under `profile` differs from what chirp
+/// assumes, which is the modern profile. Quirks that only show at run time,
+/// like sprites wrapping, can't be checked here.
+pub fn quirks(program: &Program, profile: Profile) -> Vec<Diagnostic> {
+    let quirks = profile.quirks();
+    let mut diagnostics = vec![];
+    let warning =
+        |span: Span, message: String| Diagnostic::warning(span, message).with_code(codes::QUIRK);
+
+    let instructions: Vec<&Stmt> = program
+        .statements
+        .iter()
+        .filter(|stmt| {
+            !matches!(
+                stmt.node,
+                DeclareLabel(_) | DeclareSprite(..) | DeclareConstant(..)
+            )
+        })
+        .collect();
+
+    for (index, stmt) in instructions.iter().enumerate() {
+        let first = stmt.operands.first().copied().unwrap_or(stmt.span);
+
+        match stmt.node {
+            ShiftRight(x) | ShiftLeft(x) if quirks.shift_uses_vy && x != 0 => {
+                let mnemonic = if matches!(stmt.node, ShiftRight(_)) {
+                    "shr"
+                } else {
+                    "shl"
+                };
+                diagnostics.push(warning(
+                    first,
+                    format!(
+                        "under {} `{} v{}` shifts v0 into v{}, not v{} itself",
+                        profile, mnemonic, x, x, x
+                    ),
+                ));
+            }
+            Save(x) | Load(x) if quirks.memory_increment != MemoryIncrement::None => {
+                // only the straight line after it is followed, up to the
+                // first instruction that reads or sets i
+                let reader = instructions[index + 1..]
+                    .iter()
+                    .find(|next| reads_i(next) || writes_i(next) || branches(next))
+                    .filter(|next| reads_i(next));
+                if let Some(reader) = reader {
+                    let by = match quirks.memory_increment {
+                        MemoryIncrement::ByX => x as u16,
+                        _ => x as u16 + 1,
+                    };
+                    diagnostics.push(
+                        warning(
+                            stmt.span,
+                            format!(
+                                "under {} this moves i on by {}, which a later instruction reads",
+                                profile, by
+                            ),
+                        )
+                        .with_label(reader.span, "reads i here".to_string()),
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // `interp` checks the ones naming a constant once it knows the address
+    diagnostics.extend(
+        instructions
+            .iter()
+            .filter(|stmt| stmt.values.is_empty())
+            .filter_map(|stmt| jump_register(stmt, profile)),
+    );
+    diagnostics
+}
+
+/// Warns about a `jmpr` that adds vx, x being the top nibble of its
+/// address, instead of v0 under `profile`.
+pub fn jump_register(stmt: &Stmt, profile: Profile) -> Option<Diagnostic> {
+    let JumpRegister(nnn) = stmt.node else {
+        return None;
+    };
+    if !profile.quirks().jump_uses_vx || nnn >> 8 == 0 {
+        return None;
+    }
+
+    let span = stmt.operands.first().copied().unwrap_or(stmt.span);
+    Some(
+        Diagnostic::warning(
+            span,
+            format!(
+                "under {} `jmpr {:#05x}` adds v{} instead of v0",
+                profile,
+                nnn,
+                nnn >> 8
+            ),
+        )
+        .with_code(codes::QUIRK),
+    )
+}
+
+/// Rejects instructions `target` doesn't have. Which instruction a `drw`
+/// is depends on its row count, so ones naming a constant are left for
+/// `interp` to check with `unsupported`.
+pub fn target(program: &Program, target: Target) -> Vec<Diagnostic> {
+    program
+        .statements
+        .iter()
+        .filter(|stmt| stmt.values.is_empty())
+        .filter_map(|stmt| unsupported(stmt, target))
+        .collect()
+}
+
+pub fn unsupported(stmt: &Stmt, target: Target) -> Option<Diagnostic> {
+    if target.supports(&stmt.node) {
+        return None;
+    }
+
+    let needed = Target::introducing(&stmt.node);
+    let text = stmt.node.to_string();
+    let mnemonic = text.split_whitespace().next().unwrap_or_default();
+    let what = match stmt.node {
+        Draw(..) => "`drw` with 0 rows draws a 16x16 sprite, which".to_string(),
+        SaveRange(..) | LoadRange(..) => {
+            format!("`{}` with a range of registers", mnemonic)
+        }
+        MoveIRegisterLong(_) | MoveIRegisterLongSprite(_) => "`mov i,long`".to_string(),
+        _ => format!("`{}`", mnemonic),
+    };
+    Some(
+        Diagnostic::error(
+            stmt.span,
+            format!(
+                "{} needs --target {} or later, this is building for {}",
+                what, needed, target
+            ),
+        )
+        .with_code(codes::UNSUPPORTED_INSTRUCTION),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assemble, assemble_with, Options};
+
+    use super::*;
+
+    /// Each error for `source`, as its code and the text it points at.
+    fn errors(source: &str) -> Vec<(&'static str, &str)> {
+        match assemble(source) {
+            Ok(_) => vec![],
+            Err(diagnostics) => diagnostics
+                .iter()
+                .filter_map(|d| Some((d.code?, &source[d.span.lo..d.span.hi])))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn widths() {
+        let source = "\
+drw v0,v1,16
+drw v0,v1,15
+mov v0,256
+rnd v0,255
+jmp 0x1000
+mov i,0xfff
+$s 0x100 0xff
+";
+        let out_of_range = codes::OPERAND_OUT_OF_RANGE;
+        assert_eq!(
+            errors(source),
+            [
+                (out_of_range, "16"),
+                (out_of_range, "256"),
+                (out_of_range, "0x1000"),
+                (out_of_range, "0x100"),
+            ]
+        );
+    }
+
+    #[test]
+    fn flag_register() {
+        let invalid = codes::INVALID_REGISTER;
+        assert_eq!(errors("mov v15,1"), [(invalid, "v15")]);
+        assert_eq!(errors("add v15,v1"), [(invalid, "v15")]);
+        assert_eq!(errors("shr v15"), [(invalid, "v15")]);
+        // reading it, or saving and loading it with the rest, is fine
+        let source = "\
+se v15,1
+mov v0,v15
+save v15
+load v15
+";
+        assert_eq!(errors(source), []);
+    }
+
+    /// Each warning for `source` under `profile`, as the text it points at
+    /// and the text its labels point at.
+    fn quirk_warnings(source: &str, profile: Profile) -> Vec<(&str, Vec<&str>)> {
+        let options = Options {
+            quirks: profile,
+            ..Options::default()
+        };
+        let Ok(assembly) = assemble_with(source, &options) else {
+            panic!("{:?} failed", source);
+        };
+        let text = |span: Span| &source[span.lo..span.hi];
+        assembly
+            .diagnostics
+            .iter()
+            .filter(|d| d.code == Some(codes::QUIRK))
+            .map(|d| {
+                (
+                    text(d.span),
+                    d.labels.iter().map(|l| text(l.span)).collect(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn quirks() {
+        assert_eq!(
+            quirk_warnings("shr v3\nshl v0\n", Profile::CosmacVip),
+            [("v3", vec![])]
+        );
+        assert_eq!(quirk_warnings("shr v3\n", Profile::Modern), []);
+        assert_eq!(
+            quirk_warnings("jmpr 0x300\njmpr 0x0ff\n", Profile::Chip48),
+            [("0x300", vec![])]
+        );
+    }
+
+    #[test]
+    fn memory_increment() {
+        // i is read three instructions on, past a label and a skip
+        let source = "\
+save v3
+add v0,1
+loop:
+se v0,2
+drw v0,v1,5
+";
+        assert_eq!(
+            quirk_warnings(source, Profile::CosmacVip),
+            [("save v3", vec!["drw v0,v1,5"])]
+        );
+        assert_eq!(quirk_warnings(source, Profile::Modern), []);
+        // setting i again, or leaving the straight line, ends the search
+        assert_eq!(
+            quirk_warnings("load v3\nmov i,0x300\ndrw v0,v1,5\n", Profile::CosmacVip),
+            []
+        );
+        assert_eq!(
+            quirk_warnings("load v3\nret\ndrw v0,v1,5\n", Profile::CosmacVip),
+            []
+        );
+    }
+
+    #[test]
+    fn flag_registers() {
+        let options = Options {
+            target: Target::Schip,
+            ..Options::default()
+        };
+        let diagnostics = match assemble_with("savef v7\nloadf v8\n", &options) {
+            Ok(_) => panic!("loadf v8 assembled"),
+            Err(diagnostics) => diagnostics,
+        };
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, Some(codes::OPERAND_OUT_OF_RANGE));
+        assert_eq!(
+            diagnostics[0].message,
+            "there are only 8 flag registers, v8 is past v7"
+        );
+    }
+
+    #[test]
+    fn planes() {
+        let options = Options {
+            target: Target::XoChip,
+            ..Options::default()
+        };
+        assert!(assemble_with("plane 3", &options).is_ok());
+        let diagnostics = match assemble_with("plane 4", &options) {
+            Ok(_) => panic!("plane 4 assembled"),
+            Err(diagnostics) => diagnostics,
+        };
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, Some(codes::OPERAND_OUT_OF_RANGE));
+    }
+
+    #[test]
+    fn skipped_long_load() {
+        let options = Options {
+            target: Target::XoChip,
+            ..Options::default()
+        };
+        let source = "\
+se v0,1
+mov i,long 0x1234
+sne v0,1
+mov i,0x234
+";
+        let Ok(assembly) = assemble_with(source, &options) else {
+            panic!("{:?} failed", source);
+        };
+        let warnings: Vec<_> = assembly
+            .diagnostics
+            .iter()
+            .map(|d| (d.code, &source[d.span.lo..d.span.hi]))
+            .collect();
+        assert_eq!(warnings, [(Some(codes::SKIPPED_LONG_LOAD), "se v0,1")]);
+        let label = &assembly.diagnostics[0].labels[0];
+        assert_eq!(&source[label.span.lo..label.span.hi], "mov i,long 0x1234");
+    }
+}
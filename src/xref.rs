@@ -0,0 +1,179 @@
+//! Where each label, sprite and constant is declared and used.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    lexer::Span,
+    parser::{Program, Stmt_::*},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Kind {
+    Label,
+    Sprite,
+    Constant,
+    /// An undeclared name in an expression, which could have been any of
+    /// the others.
+    Unknown,
+}
+
+impl Kind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Label => "label",
+            Kind::Sprite => "sprite",
+            Kind::Constant => "constant",
+            Kind::Unknown => "unknown",
+        }
+    }
+}
+
+pub struct Symbol<'a> {
+    pub name: &'a str,
+    pub kind: Kind,
+    /// Spans of the name in each declaration; more than one is a duplicate,
+    /// none means it is used without being declared.
+    pub definitions: Vec<Span>,
+    /// Spans of the name in every operand naming it.
+    pub references: Vec<Span>,
+}
+
+/// Every label, sprite and constant in `program`, ordered by kind then name.
+///
+/// Works on the parsed program alone, so it still covers programs that
+/// fail to assemble.
+pub fn cross_reference(program: &Program) -> Vec<Symbol<'_>> {
+    let mut symbols: BTreeMap<(Kind, &str), Symbol> = BTreeMap::new();
+
+    let mut declared: HashMap<&str, Kind> = HashMap::new();
+    for stmt in &program.statements {
+        let (name, kind) = match stmt.node {
+            DeclareLabel(ref name) => (name, Kind::Label),
+            DeclareSprite(ref name, _) => (name, Kind::Sprite),
+            DeclareConstant(ref name, _) => (name, Kind::Constant),
+            _ => continue,
+        };
+        // a name `jmp`, `call` or `mov i` takes as a label or sprite may be
+        // any kind instead, and a constant wins a clash
+        let entry = declared.entry(name).or_insert(kind);
+        if kind == Kind::Constant {
+            *entry = kind;
+        }
+    }
+    let kind_of = |name: &str, kind: Kind| declared.get(name).copied().unwrap_or(kind);
+    // a name in an expression can be any of them
+    let expression_kind = |name: &str| kind_of(name, Kind::Unknown);
+
+    for stmt in &program.statements {
+        let name_span = |operand: usize| stmt.operands.get(operand).copied().unwrap_or(stmt.span);
+        let reference = stmt.operands.last().copied().unwrap_or(stmt.span);
+        let mut uses: Vec<(Kind, &str, Span, bool)> = vec![];
+        match stmt.node {
+            DeclareLabel(ref name) => uses.push((Kind::Label, name, stmt.span, true)),
+            DeclareSprite(ref name, _) => uses.push((Kind::Sprite, name, name_span(0), true)),
+            DeclareConstant(ref name, ref value) => {
+                uses.push((Kind::Constant, name, name_span(0), true));
+                for (name, span) in value.names() {
+                    uses.push((expression_kind(name), name, span, false));
+                }
+            }
+            JumpLabel(ref name) | CallLabel(ref name) => {
+                uses.push((kind_of(name, Kind::Label), name, reference, false))
+            }
+            MoveIRegisterSprite(ref name) | MoveIRegisterLongSprite(ref name) => {
+                uses.push((kind_of(name, Kind::Sprite), name, reference, false))
+            }
+            _ => {}
+        }
+        for (_, value) in &stmt.values {
+            for (name, span) in value.names() {
+                uses.push((expression_kind(name), name, span, false));
+            }
+        }
+
+        for (kind, name, span, is_definition) in uses {
+            let symbol = symbols.entry((kind, name)).or_insert_with(|| Symbol {
+                name,
+                kind,
+                definitions: vec![],
+                references: vec![],
+            });
+            if is_definition {
+                symbol.definitions.push(span);
+            } else {
+                symbol.references.push(span);
+            }
+        }
+    }
+
+    symbols.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::Lexer, parser::parse};
+
+    /// Each symbol as (kind, name, definitions, references), with spans as
+    /// the text they cover.
+    fn symbols(source: &str) -> Vec<(Kind, String, Vec<&str>, Vec<&str>)> {
+        let (program, _) = parse(Lexer::new(source), source.len());
+        let text = |span: &Span| &source[span.lo..span.hi];
+        cross_reference(&program)
+            .iter()
+            .map(|symbol| {
+                (
+                    symbol.kind,
+                    symbol.name.to_string(),
+                    symbol.definitions.iter().map(text).collect(),
+                    symbol.references.iter().map(text).collect(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn declarations_and_references() {
+        let source = "\
+start:
+    mov i,ball
+    call draw
+    jmp  start
+idle:
+$ball 0x80
+$ball 0x40
+";
+        assert_eq!(
+            symbols(source),
+            [
+                (Kind::Label, "draw".into(), vec![], vec!["draw"]),
+                (Kind::Label, "idle".into(), vec!["idle:"], vec![]),
+                (Kind::Label, "start".into(), vec!["start:"], vec!["start"]),
+                (
+                    Kind::Sprite,
+                    "ball".into(),
+                    vec!["ball", "ball"],
+                    vec!["ball"]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn expression_names() {
+        let source = "\
+start:
+    mov i,start
+    mov v0,WIDTH + missing
+const WIDTH = 8
+";
+        assert_eq!(
+            symbols(source),
+            [
+                (Kind::Label, "start".into(), vec!["start:"], vec!["start"]),
+                (Kind::Constant, "WIDTH".into(), vec!["WIDTH"], vec!["WIDTH"]),
+                (Kind::Unknown, "missing".into(), vec![], vec!["missing"]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,82 @@
+//! Runs the chirp binary the way CI and editor plugins do.
+
+use std::{
+    env, fs,
+    path::PathBuf,
+    process::{Command, Output},
+};
+
+/// A path in the temp directory that no other test uses.
+fn temp(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("chirp-{}-{}", std::process::id(), name))
+}
+
+fn chirp(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_chirp"))
+        .args(args)
+        .output()
+        .expect("chirp runs")
+}
+
+#[test]
+fn xref() {
+    let input = temp("xref.s");
+    fs::write(&input, "start:\ncall draw\njmp start\nidle:\n$ball 0x80\n").unwrap();
+    let path = input.to_str().unwrap();
+
+    let result = chirp(&["xref", path]);
+    assert!(result.status.success());
+    let stdout = String::from_utf8(result.stdout).unwrap();
+    let expected = [
+        "label draw  undeclared".to_string(),
+        format!("    {}:2:6  call draw", path),
+        format!("label idle  {}:4:1  unused", path),
+        format!("label start  {}:1:1", path),
+        format!("    {}:3:5  jmp start", path),
+        format!("sprite ball  {}:5:2  unused", path),
+        String::new(),
+        "4 symbols, 2 unused, 1 undeclared".to_string(),
+    ];
+    assert_eq!(stdout.lines().collect::<Vec<_>>(), expected);
+
+    // a name picks out its symbol and leaves off the totals
+    let result = chirp(&["xref", path, "start"]);
+    assert!(result.status.success());
+    let stdout = String::from_utf8(result.stdout).unwrap();
+    assert_eq!(stdout.lines().collect::<Vec<_>>(), expected[3..5]);
+
+    let result = chirp(&["xref", path, "missing"]);
+    assert_eq!(result.status.code(), Some(2));
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    assert!(stderr.starts_with("no label or sprite named \"missing\""));
+
+    let _ = fs::remove_file(input);
+}
+
+#[test]
+fn json_memory_map_goes_to_stderr() {
+    let input = temp("map.s");
+    let output = temp("map.ch8");
+    fs::write(&input, "a:\na:\ncls\n").unwrap();
+
+    let result = chirp(&[
+        "--message-format=json",
+        "--memory-map",
+        input.to_str().unwrap(),
+        output.to_str().unwrap(),
+    ]);
+    assert!(result.status.success());
+
+    // stdout is only the duplicate label warning, one object per line
+    let stdout = String::from_utf8(result.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].starts_with('{') && lines[0].ends_with('}'));
+    assert!(lines[0].contains("\"code\":\"W0001\""));
+
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    assert!(stderr.starts_with("; memory map"));
+
+    let _ = fs::remove_file(input);
+    let _ = fs::remove_file(output);
+}